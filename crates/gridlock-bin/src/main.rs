@@ -3,11 +3,13 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use color_eyre::eyre::eyre;
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use color_eyre::eyre::{eyre, Context};
 use gridlock::{
-    plan_update, read_lockfile, write_lockfile, GitHubClient, Lock, Lockfile, LockfileChange,
-    OnlineGitHubClient, Value,
+    plan_update, read_lockfile, write_lockfile, BinaryCache, GitHubClient, Lock, LockOptions,
+    Lockfile, LockfileChange, OnlineGitHubClient, Value,
 };
+use nyarr::{signing::SecretKey, tar::UnsupportedEntries};
 use owo_colors::OwoColorize;
 
 #[derive(clap::Parser)]
@@ -16,6 +18,27 @@ struct Args {
     #[clap(long)]
     lockfile: PathBuf,
 
+    /// What to do about device nodes, FIFOs and other archive entries that
+    /// cannot be put in a NAR: refuse to lock the archive, print a warning and
+    /// leave them out, or silently leave them out.
+    #[clap(
+        long,
+        global = true,
+        default_value_t,
+        value_parser = PossibleValuesParser::new(UnsupportedEntries::NAMES)
+            .map(|s| s.parse::<UnsupportedEntries>().unwrap()),
+    )]
+    unsupported_entries: UnsupportedEntries,
+
+    /// Also put everything that gets locked into this `file://` binary cache.
+    #[clap(long, global = true)]
+    binary_cache: Option<PathBuf>,
+
+    /// Secret key to sign binary cache entries with, as made by
+    /// `nix key generate-secret`. Only for `--binary-cache` and `cache-export`.
+    #[clap(long, global = true)]
+    signing_key: Option<PathBuf>,
+
     #[clap(subcommand)]
     subcommand: Subcommand,
 }
@@ -40,6 +63,13 @@ struct Add {
     name: Option<String>,
 }
 
+#[derive(clap::Parser)]
+struct CacheExport {
+    /// Directory to write the binary cache into. Nix can use it as
+    /// `file:///path/to/dir`.
+    dir: PathBuf,
+}
+
 #[derive(clap::Parser)]
 struct MetaSetInsert {
     /// Package name to edit.
@@ -77,6 +107,8 @@ enum Subcommand {
     Init,
     #[clap(subcommand)]
     Meta(Meta),
+    /// Download every locked source again and put it in a binary cache.
+    CacheExport(CacheExport),
 }
 
 fn boldprint(head: &str, f: impl std::fmt::Display) {
@@ -101,6 +133,9 @@ async fn do_show(lockfile_path: &Path) -> color_eyre::Result<()> {
                 })
                 .unwrap_or("Unknown".into()),
         );
+        if let Some(store_path) = &package.store_path {
+            boldprint("Store path", store_path);
+        }
         boldprint(
             "Web link",
             format!(
@@ -112,16 +147,15 @@ async fn do_show(lockfile_path: &Path) -> color_eyre::Result<()> {
     Ok(())
 }
 
-async fn do_update(lockfile_path: &Path, update: Update) -> color_eyre::Result<()> {
+async fn do_update(
+    lockfile_path: &Path,
+    update: Update,
+    options: &LockOptions,
+) -> color_eyre::Result<()> {
     let mut lockfile = read_lockfile(lockfile_path).await?;
     let client = OnlineGitHubClient::new()?;
 
-    let plan = plan_update(
-        &client,
-        &lockfile,
-        update.package_name.as_ref().map(String::as_str),
-    )
-    .await?;
+    let plan = plan_update(&client, &lockfile, update.package_name.as_deref()).await?;
 
     println!("Plan: {plan:?}");
 
@@ -130,7 +164,7 @@ async fn do_update(lockfile_path: &Path, update: Update) -> color_eyre::Result<(
             LockfileChange::UpdateRev(name, rev) => {
                 let p = lockfile.packages.get_mut(&name).unwrap();
                 let new_lock = client
-                    .create_lock(&p.owner, &p.repo, &p.branch, &rev)
+                    .create_lock(&p.owner, &p.repo, &p.branch, &rev, options)
                     .await?;
                 *p = Lock {
                     extra: std::mem::take(&mut p.extra),
@@ -145,7 +179,7 @@ async fn do_update(lockfile_path: &Path, update: Update) -> color_eyre::Result<(
     Ok(())
 }
 
-async fn do_add(lockfile_path: &Path, add: Add) -> color_eyre::Result<()> {
+async fn do_add(lockfile_path: &Path, add: Add, options: &LockOptions) -> color_eyre::Result<()> {
     let client = OnlineGitHubClient::new()?;
 
     let mut lockfile = read_lockfile(lockfile_path).await?;
@@ -162,7 +196,9 @@ async fn do_add(lockfile_path: &Path, add: Add) -> color_eyre::Result<()> {
     let item_name = add.name.unwrap_or_else(|| repo.to_string());
 
     println!("Adding {owner}/{repo} at {branch_name}: {head}");
-    let lock = client.create_lock(owner, repo, &branch_name, &head).await?;
+    let lock = client
+        .create_lock(owner, repo, &branch_name, &head, options)
+        .await?;
 
     let old = lockfile.packages.entry(item_name);
     // Maintain extra information across multiple adds.
@@ -187,6 +223,26 @@ async fn do_add(lockfile_path: &Path, add: Add) -> color_eyre::Result<()> {
     Ok(())
 }
 
+async fn do_cache_export(lockfile_path: &Path, options: &LockOptions) -> color_eyre::Result<()> {
+    let lockfile = read_lockfile(lockfile_path).await?;
+    let client = OnlineGitHubClient::new()?;
+
+    for (name, lock) in &lockfile.packages {
+        println!("Exporting {name} at {}", lock.rev);
+        // a source that changed is not what the lockfile means, so keep it out
+        // of the cache
+        let options = LockOptions {
+            expected_sha256: Some(lock.sha256.clone()),
+            ..options.clone()
+        };
+        client
+            .create_lock(&lock.owner, &lock.repo, &lock.branch, &lock.rev, &options)
+            .await
+            .with_context(|| format!("exporting {name}"))?;
+    }
+    Ok(())
+}
+
 async fn do_init(lockfile_path: &Path) -> color_eyre::Result<()> {
     let lockfile = Lockfile::default();
     write_lockfile(lockfile_path, &lockfile).await?;
@@ -249,12 +305,53 @@ async fn do_meta(lockfile_path: &Path, meta: Meta) -> color_eyre::Result<()> {
 async fn main() -> color_eyre::Result<()> {
     color_eyre::install()?;
     let args = <Args as clap::Parser>::parse();
+    if args.signing_key.is_some()
+        && args.binary_cache.is_none()
+        && !matches!(args.subcommand, Subcommand::CacheExport(_))
+    {
+        return Err(eyre!(
+            "--signing-key only applies to --binary-cache and cache-export"
+        ));
+    }
+    if args.binary_cache.is_some() && matches!(args.subcommand, Subcommand::CacheExport(_)) {
+        return Err(eyre!(
+            "cache-export takes the cache directory as an argument, not --binary-cache"
+        ));
+    }
+    let signing_key = match &args.signing_key {
+        Some(path) => Some(
+            std::fs::read_to_string(path)
+                .context("reading signing key")?
+                .parse::<SecretKey>()?,
+        ),
+        None => None,
+    };
+    let options = LockOptions {
+        strictness: args
+            .unsupported_entries
+            .strictness(|e| eprintln!("{}: skipping {e}", "warning".yellow().bold())),
+        binary_cache: args.binary_cache.map(|dir| BinaryCache {
+            dir,
+            signing_key: signing_key.clone(),
+        }),
+        ..Default::default()
+    };
 
     match args.subcommand {
-        Subcommand::Update(u) => do_update(&args.lockfile, u).await,
+        Subcommand::Update(u) => do_update(&args.lockfile, u, &options).await,
         Subcommand::Show => do_show(&args.lockfile).await,
-        Subcommand::Add(a) => do_add(&args.lockfile, a).await,
+        Subcommand::Add(a) => do_add(&args.lockfile, a, &options).await,
         Subcommand::Init => do_init(&args.lockfile).await,
         Subcommand::Meta(meta) => do_meta(&args.lockfile, meta).await,
+        Subcommand::CacheExport(export) => {
+            let options = LockOptions {
+                binary_cache: Some(BinaryCache {
+                    dir: export.dir,
+                    signing_key,
+                }),
+                ..options
+            };
+            do_cache_export(&args.lockfile, &options).await
+        }
     }
 }
@@ -3,21 +3,35 @@
 
 use std::{
     collections::{BTreeMap, HashMap},
-    io::{Cursor, Read},
-    path::Path,
+    fs::File,
+    io::{self, BufReader, BufWriter, Cursor, Read, Seek, Write},
+    path::{Path, PathBuf},
     process::Stdio,
 };
 
 use async_trait::async_trait;
 use chrono::Utc;
 use color_eyre::eyre::{eyre, Context};
+use nyarr::{
+    compression::{CompressedNarWriter, Compression, NarFileInfo},
+    hash::{Hash, HashAlgo, HashMode, NarHasher},
+    ls::Listing,
+    narinfo::{ContentAddress, NarInfo},
+    signing::SecretKey,
+    store_path::StorePath,
+    tar::Streamed,
+};
 use regex::Regex;
 use serde::{de::Visitor, Deserialize, Serialize, Serializer};
 pub use serde_json::Value;
-use tokio::{fs, io::AsyncWriteExt};
+use tempfile::NamedTempFile;
+use tokio::{fs, io::AsyncWriteExt, sync::mpsc};
 
 const LOCKFILE_VERSION: u16 = 0;
 
+/// Name that Nix fetchers give their results by default.
+const SOURCE_NAME: &str = "source";
+
 /// Lockfile format, loosely based on Niv's format, since it's simple and
 /// mostly a good design.
 #[derive(Deserialize, Serialize)]
@@ -92,6 +106,69 @@ impl Serialize for UnixTimestamp {
     }
 }
 
+/// sha256 hash of a locked source. Any format Nix accepts is read, and is
+/// written back as it was so that untouched entries of a lockfile stay the
+/// same. New hashes are written as SRI.
+#[derive(Debug, Clone)]
+pub struct LockHash(pub nyarr::hash::Hash, Option<String>);
+
+impl LockHash {
+    pub fn new(hash: nyarr::hash::Hash) -> LockHash {
+        LockHash(hash, None)
+    }
+}
+
+impl PartialEq for LockHash {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for LockHash {}
+
+impl<'de> Deserialize<'de> for LockHash {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct MyVisitor {}
+
+        impl<'de> Visitor<'de> for MyVisitor {
+            type Value = LockHash;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a sha256 hash in SRI, base32 or hex format")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                nyarr::hash::Hash::parse(v, Some(HashAlgo::Sha256))
+                    .map(|hash| LockHash(hash, Some(v.to_string())))
+                    .map_err(serde::de::Error::custom)
+            }
+        }
+
+        deserializer.deserialize_str(MyVisitor {})
+    }
+}
+
+impl Serialize for LockHash {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match &self.1 {
+            // unless the hash has been changed since
+            Some(read) if Hash::parse(read, Some(HashAlgo::Sha256)).as_ref() == Ok(&self.0) => {
+                serializer.serialize_str(read)
+            }
+            _ => serializer.serialize_str(&self.0.to_sri()),
+        }
+    }
+}
+
 pub type GitRevision = String;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -100,7 +177,15 @@ pub struct Lock {
     pub owner: String,
     pub repo: String,
     pub rev: GitRevision,
-    pub sha256: String,
+    /// Recursive NAR hash of the unpacked archive, for `fetchzip` and friends.
+    pub sha256: LockHash,
+    /// Flat hash of the archive itself, for `fetchurl` and the like.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archive_sha256: Option<LockHash>,
+    /// Where `fetchzip` or `builtins.fetchTarball` will put the source, with
+    /// their default name of `source`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub store_path: Option<String>,
     pub last_updated: Option<UnixTimestamp>,
     pub url: String,
     #[serde(flatten)]
@@ -111,6 +196,134 @@ fn archive_url(owner: &str, repo: &str, rev: &str) -> String {
     format!("https://github.com/{owner}/{repo}/archive/{rev}.tar.gz")
 }
 
+/// Knobs for [`GitHubClient::create_lock`].
+#[derive(Clone, Debug)]
+pub struct LockOptions {
+    /// What to do about archive entries that cannot be represented in a NAR.
+    pub strictness: nyarr::tar::Strictness,
+    /// Where to put the locked source so Nix need not download it again.
+    pub binary_cache: Option<BinaryCache>,
+    /// Fail, before anything is put in the binary cache, unless the source
+    /// has this hash.
+    pub expected_sha256: Option<LockHash>,
+}
+
+impl Default for LockOptions {
+    fn default() -> Self {
+        LockOptions {
+            strictness: nyarr::tar::Strictness::Error,
+            binary_cache: None,
+            expected_sha256: None,
+        }
+    }
+}
+
+/// A `file://` binary cache, which Nix can substitute locked sources from
+/// instead of downloading them from GitHub a second time.
+#[derive(Clone, Debug)]
+pub struct BinaryCache {
+    pub dir: PathBuf,
+    /// Key to sign paths with. Nix takes content-addressed paths without
+    /// signatures, but a key lets the cache be trusted for anything.
+    pub signing_key: Option<SecretKey>,
+}
+
+const CACHE_INFO: &str = "StoreDir: /nix/store\n";
+
+/// A NAR being compressed into a [`BinaryCache`]. Nothing shows up in the
+/// cache until it is [`finish`](NarWriter::finish)ed and
+/// [`insert`](BinaryCache::insert)ed, and dropping it leaves nothing behind.
+pub struct NarWriter(CompressedNarWriter<BufWriter<NamedTempFile>>);
+
+impl Write for NarWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl NarWriter {
+    pub fn finish(self) -> io::Result<StagedNar> {
+        let (file, info) = self.0.finish()?;
+        Ok(StagedNar {
+            file: file.into_inner().map_err(|e| e.into_error())?,
+            info,
+        })
+    }
+}
+
+/// A compressed NAR waiting to be [`insert`](BinaryCache::insert)ed.
+pub struct StagedNar {
+    file: NamedTempFile,
+    pub info: NarFileInfo,
+}
+
+impl BinaryCache {
+    /// Starts writing a NAR into the cache, compressed as it is written.
+    pub fn nar_writer(&self) -> io::Result<NarWriter> {
+        let dir = self.dir.join("nar");
+        std::fs::create_dir_all(&dir)?;
+        let file = NamedTempFile::new_in(dir)?;
+        Ok(NarWriter(CompressedNarWriter::new(
+            Compression::Xz,
+            BufWriter::new(file),
+        )?))
+    }
+
+    /// Adds `nar` to the cache as the contents of `store_path`, an
+    /// unreferenced source path.
+    pub async fn insert(&self, store_path: &StorePath, nar: StagedNar) -> color_eyre::Result<()> {
+        let StagedNar { file, info: nar } = nar;
+        let (_, contents) = nyarr::compression::decompress(BufReader::new(file.reopen()?))?;
+        let listing = Listing::read_nar(contents).context("reading back our own NAR")?;
+        let mut ls = Vec::new();
+        listing.write_json(&mut ls)?;
+
+        let url = format!("nar/{}.nar.xz", nar.file_hash.to_nix_base32());
+        let mut info = NarInfo {
+            store_path: store_path.clone(),
+            url: url.clone(),
+            compression: Compression::Xz.into(),
+            file_hash: Some(nar.file_hash),
+            file_size: Some(nar.file_size),
+            nar_hash: nar.nar_hash.clone(),
+            nar_size: nar.nar_size,
+            references: Vec::new(),
+            deriver: None,
+            sigs: Vec::new(),
+            ca: Some(ContentAddress::Fixed(HashMode::Recursive, nar.nar_hash)),
+        };
+        if let Some(key) = &self.signing_key {
+            info.sign(key);
+        }
+
+        if fs::metadata(self.dir.join("nix-cache-info")).await.is_err() {
+            fs::write(self.dir.join("nix-cache-info"), CACHE_INFO).await?;
+        }
+        // temporary files are only readable by their owner
+        #[cfg(unix)]
+        {
+            use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+            file.as_file()
+                .set_permissions(Permissions::from_mode(0o644))?;
+        }
+        file.persist(self.dir.join(&url)).map_err(|e| e.error)?;
+        let hash_part = store_path.hash_part();
+        fs::write(self.dir.join(format!("{hash_part}.ls")), &ls).await?;
+        // last, so that the cache never points at a missing NAR
+        fs::write(
+            self.dir.join(format!("{hash_part}.narinfo")),
+            info.to_string(),
+        )
+        .await
+        .context("writing narinfo")?;
+        Ok(())
+    }
+}
+
 /// Some implementation of a client to do online stuff with GitHub.
 /// Installed as an extension/mocking point.
 #[async_trait]
@@ -128,6 +341,7 @@ pub trait GitHubClient {
         repo: &str,
         branch: &str,
         rev: &str,
+        options: &LockOptions,
     ) -> color_eyre::Result<Lock>;
 }
 
@@ -229,6 +443,124 @@ async fn git_branch_head(
     Ok((val, branch_name))
 }
 
+/// Reads what a download sends down `rx`, so that the blocking archive code
+/// can work on it as it arrives.
+struct ChannelReader {
+    rx: mpsc::Receiver<Vec<u8>>,
+    chunk: Cursor<Vec<u8>>,
+}
+
+impl ChannelReader {
+    fn new(rx: mpsc::Receiver<Vec<u8>>) -> ChannelReader {
+        ChannelReader {
+            rx,
+            chunk: Cursor::default(),
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.chunk.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            match self.rx.blocking_recv() {
+                Some(chunk) => self.chunk = Cursor::new(chunk),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Passes an archive through, hashing it and keeping a copy on disk in case it
+/// cannot be streamed.
+struct Spooled<R> {
+    r: R,
+    hasher: NarHasher,
+    spool: File,
+}
+
+impl<R: Read> Read for Spooled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.r.read(buf)?;
+        self.hasher.write_all(&buf[..n])?;
+        self.spool.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+/// Unpacks a GitHub archive the way `fetchTarball` does, into a NAR, reading
+/// it only once. Returns the flat hash of the archive and the writer from
+/// `output`, which is called again if the archive has to be unpacked out of
+/// order.
+fn archive_to_nar<W: Write>(
+    archive: impl Read,
+    mut output: impl FnMut() -> io::Result<W>,
+    options: &LockOptions,
+) -> color_eyre::Result<(Hash, W)> {
+    let options = nyarr::tar::Tar2NarOptions {
+        strip_root: nyarr::tar::StripRoot::SingleRoot,
+        strictness: options.strictness.clone(),
+    };
+    let mut archive = Spooled {
+        r: archive,
+        hasher: NarHasher::new(),
+        spool: tempfile::tempfile()?,
+    };
+
+    let mut w = output()?;
+    let streamed = nyarr::tar::stream_tar_to_nar(
+        nyarr::compression::decompress(&mut archive)?.1,
+        &mut w,
+        &options,
+    )
+    .map_err(|e| eyre!(e))?;
+    // whatever follows the tar still counts towards the archive hash
+    io::copy(&mut archive, &mut io::sink())?;
+    if streamed == Streamed::Done {
+        return Ok((archive.hasher.finish(), w));
+    }
+    drop(w);
+
+    let Spooled {
+        hasher, mut spool, ..
+    } = archive;
+    spool.rewind()?;
+    let mut w = output()?;
+    nyarr::tar::spooled_tar_to_nar(
+        nyarr::compression::decompress(BufReader::new(spool))?.1,
+        &mut w,
+        &options,
+    )
+    .map_err(|e| eyre!(e))?;
+    Ok((hasher.finish(), w))
+}
+
+/// Unpacks the archive that `resp` is downloading as it arrives, with
+/// [`archive_to_nar`] on a blocking thread.
+async fn unpack_download<W: Write + Send + 'static>(
+    mut resp: reqwest::Response,
+    output: impl FnMut() -> io::Result<W> + Send + 'static,
+    options: &LockOptions,
+) -> color_eyre::Result<(Hash, W)> {
+    let (tx, rx) = mpsc::channel(16);
+    let options = options.clone();
+    let unpack = tokio::task::spawn_blocking(move || {
+        archive_to_nar(ChannelReader::new(rx), output, &options)
+    });
+
+    while let Some(chunk) = resp.chunk().await? {
+        if tx.send(chunk.to_vec()).await.is_err() {
+            // unpacking has failed, and says why below
+            break;
+        }
+    }
+    drop(tx);
+    unpack.await?
+}
+
 #[async_trait]
 impl GitHubClient for OnlineGitHubClient {
     async fn branch_head(
@@ -246,24 +578,45 @@ impl GitHubClient for OnlineGitHubClient {
         repo: &str,
         branch: &str,
         rev: &str,
+        options: &LockOptions,
     ) -> color_eyre::Result<Lock> {
         let url = archive_url(owner, repo, rev);
-        let resp = self.client.get(&url).send().await?.bytes().await?;
-        let content = resp.to_vec();
-
-        // FIXME: add a debug option to put this tarball on disk
-        // fs::write("content.tar.gz", &content).await?;
-        let mut decoder = flate2::read::GzDecoder::new(Cursor::new(&content));
-        let mut content = Vec::new();
-        decoder.read_to_end(&mut content)?;
-
-        let mut hasher = nyarr::hash::NarHasher::new();
-        nyarr::tar::tar_to_nar(
-            Cursor::new(&content),
-            &mut hasher,
-            nyarr::tar::StripRoot::StripRoot,
-        )
-        .map_err(|e| eyre!(e))?;
+        let resp = self.client.get(&url).send().await?;
+        let (archive_hash, nar_hash, staged) = match &options.binary_cache {
+            Some(cache) => {
+                let cache = cache.clone();
+                let (archive_hash, nar) =
+                    unpack_download(resp, move || cache.nar_writer(), options).await?;
+                let staged = nar.finish()?;
+                (archive_hash, staged.info.nar_hash.clone(), Some(staged))
+            }
+            None => {
+                let (archive_hash, hasher) =
+                    unpack_download(resp, || Ok(NarHasher::new()), options).await?;
+                (archive_hash, hasher.finish(), None)
+            }
+        };
+        let store_path = nyarr::store_path::fixed_output(
+            nyarr::store_path::DEFAULT_STORE_DIR,
+            SOURCE_NAME,
+            &nar_hash,
+            HashMode::Recursive,
+        )?;
+
+        if let Some(expected) = &options.expected_sha256 {
+            if expected.0 != nar_hash {
+                return Err(eyre!(
+                    "{owner}/{repo} at {rev} should have hash {} but has {nar_hash}",
+                    expected.0
+                ));
+            }
+        }
+        if let (Some(cache), Some(staged)) = (&options.binary_cache, staged) {
+            cache
+                .insert(&store_path, staged)
+                .await
+                .context("adding to binary cache")?;
+        }
 
         Ok(Lock {
             owner: owner.into(),
@@ -272,7 +625,9 @@ impl GitHubClient for OnlineGitHubClient {
             rev: rev.into(),
             url,
             last_updated: Some(UnixTimestamp(Utc::now())),
-            sha256: hasher.digest(),
+            sha256: LockHash::new(nar_hash),
+            archive_sha256: Some(LockHash::new(archive_hash)),
+            store_path: Some(store_path.to_string()),
             extra: Default::default(),
         })
     }
@@ -378,6 +733,7 @@ mod test {
             _repo: &str,
             _branch: &str,
             _rev: &str,
+            _options: &LockOptions,
         ) -> color_eyre::Result<Lock> {
             todo!()
         }
@@ -429,6 +785,179 @@ mod test {
         serde_json::from_slice(content).unwrap()
     }
 
+    #[test]
+    fn test_hash_formats() {
+        let lock = |hash: &str| -> Lock {
+            serde_json::from_value(serde_json::json!({
+                "branch": "main",
+                "owner": "lf-",
+                "repo": "aiobspwm",
+                "rev": "fa0a22bb28c5ca5f1704a050a0bd9e3e6c9b6631",
+                "sha256": hash,
+                "last_updated": null,
+                "url": "",
+            }))
+            .unwrap()
+        };
+
+        let sri = lock("sha256-ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=");
+        for other in [
+            "1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s",
+            "sha256:1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s",
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        ] {
+            assert_eq!(lock(other).sha256, sri.sha256);
+        }
+        // written back as they were read
+        for hash in [
+            "sha256-ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=",
+            "1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s",
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        ] {
+            assert_eq!(serde_json::to_value(lock(hash)).unwrap()["sha256"], hash);
+        }
+        // but changed ones, and new ones, are SRI
+        let mut changed = lock("1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s");
+        changed.sha256.0.bytes[0] ^= 1;
+        assert!(serde_json::to_value(&changed).unwrap()["sha256"]
+            .as_str()
+            .unwrap()
+            .starts_with("sha256-"));
+        assert_eq!(
+            serde_json::to_value(LockHash::new(sri.sha256.0.clone())).unwrap(),
+            "sha256-ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0="
+        );
+        assert!(serde_json::from_value::<LockHash>("sha1-abc".into()).is_err());
+
+        // locks from before a field existed are written back unchanged
+        let written = serde_json::to_value(sri).unwrap();
+        assert!(written.get("archive_sha256").is_none());
+        assert!(written.get("store_path").is_none());
+    }
+
+    /// Feeds `archive` to [`archive_to_nar`] in small pieces from another
+    /// thread, as a download would.
+    fn unpack_in_chunks(archive: &[u8]) -> (Hash, Vec<u8>) {
+        let (tx, rx) = mpsc::channel(1);
+        let chunks = archive.chunks(100).map(<[u8]>::to_vec).collect::<Vec<_>>();
+        let sender = std::thread::spawn(move || {
+            for chunk in chunks {
+                tx.blocking_send(chunk).unwrap();
+            }
+        });
+        let unpacked = archive_to_nar(
+            ChannelReader::new(rx),
+            || Ok(Vec::new()),
+            &LockOptions::default(),
+        )
+        .unwrap();
+        sender.join().unwrap();
+        unpacked
+    }
+
+    #[test]
+    fn test_archive_to_nar() {
+        let nar = include_bytes!("../../nyarr/src/testdata/test2.nar");
+        let fso = nyarr::nar::nar_to_fsobject(&nar[..]).unwrap();
+        let mut archive = Vec::new();
+        nyarr::tar::fsobject_to_tar(&fso, Some(b"source"), &mut archive).unwrap();
+
+        let (archive_hash, out) = unpack_in_chunks(&archive);
+        assert_eq!(out, nar);
+        assert_eq!(
+            archive_hash,
+            nyarr::hash::flat_hash(&archive[..], HashAlgo::Sha256).unwrap()
+        );
+
+        // out of nar order, so it has to be unpacked again from the spool
+        let mut builder = tar::Builder::new(Vec::new());
+        for name in ["source/b", "source/a"] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(1);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, &b"x"[..]).unwrap();
+        }
+        let archive = builder.into_inner().unwrap();
+        let (archive_hash, out) = unpack_in_chunks(&archive);
+        let mut expected = Vec::new();
+        let options = nyarr::tar::Tar2NarOptions {
+            strip_root: nyarr::tar::StripRoot::SingleRoot,
+            ..Default::default()
+        };
+        nyarr::tar::tar_to_nar(Cursor::new(&archive), &mut expected, &options).unwrap();
+        assert_eq!(out, expected);
+        assert_eq!(
+            archive_hash,
+            nyarr::hash::flat_hash(&archive[..], HashAlgo::Sha256).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_binary_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = SecretKey::generate("gridlock-test-1");
+        let cache = BinaryCache {
+            dir: dir.path().to_owned(),
+            signing_key: Some(key.clone()),
+        };
+
+        let nar = include_bytes!("../../nyarr/src/testdata/test2.nar");
+        let nar_hash = nyarr::hash::flat_hash(&nar[..], HashAlgo::Sha256).unwrap();
+        let store_path = nyarr::store_path::fixed_output(
+            nyarr::store_path::DEFAULT_STORE_DIR,
+            SOURCE_NAME,
+            &nar_hash,
+            HashMode::Recursive,
+        )
+        .unwrap();
+        // an abandoned NAR leaves nothing behind
+        cache.nar_writer().unwrap().write_all(b"junk").unwrap();
+        let mut writer = cache.nar_writer().unwrap();
+        writer.write_all(nar).unwrap();
+        cache
+            .insert(&store_path, writer.finish().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_dir(dir.path().join("nar")).unwrap().count(),
+            1
+        );
+
+        let info = std::fs::read_to_string(
+            dir.path()
+                .join(format!("{}.narinfo", store_path.hash_part())),
+        )
+        .unwrap()
+        .parse::<NarInfo>()
+        .unwrap();
+        assert_eq!(info.store_path, store_path);
+        assert_eq!(info.nar_hash, nar_hash);
+        assert_eq!(info.nar_size, nar.len() as u64);
+        assert!(info.is_signed_by(&[key.to_public()]));
+
+        let compressed = std::fs::read(dir.path().join(&info.url)).unwrap();
+        assert_eq!(info.file_size, Some(compressed.len() as u64));
+        assert_eq!(
+            info.file_hash,
+            Some(nyarr::hash::flat_hash(&compressed[..], HashAlgo::Sha256).unwrap())
+        );
+        assert_eq!(
+            nyarr::compression::decompress_to_vec(&compressed[..]).unwrap(),
+            nar
+        );
+
+        let ls: Value = serde_json::from_slice(
+            &std::fs::read(dir.path().join(format!("{}.ls", store_path.hash_part()))).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(ls["version"], 1);
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("nix-cache-info")).unwrap(),
+            CACHE_INFO
+        );
+    }
+
     #[tokio::test]
     async fn test_plan_update() {
         let client = gh_client();
@@ -37,7 +37,7 @@ impl<'a> core::fmt::Display for HexDumper<'a> {
                 }
             }
             pos += 0x10;
-            write!(f, "\n")?;
+            writeln!(f)?;
         }
         Ok(())
     }
@@ -45,7 +45,7 @@ impl<'a> core::fmt::Display for HexDumper<'a> {
 
 impl HexDumper<'_> {
     /// Makes a new hex dump iterator
-    pub fn new(bytes: &[u8]) -> HexDumper {
+    pub fn new(bytes: &[u8]) -> HexDumper<'_> {
         HexDumper { bytes }
     }
 }
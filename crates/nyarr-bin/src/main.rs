@@ -1,21 +1,30 @@
 use std::{
-    ffi::OsString,
+    ffi::{OsStr, OsString},
     fs::{File, OpenOptions},
-    io::{BufReader, BufWriter, Write},
+    io::{BufReader, BufWriter, Cursor, Read, Seek, Write},
+    os::unix::ffi::OsStrExt,
     path::{Path, PathBuf},
     process::{Command, ExitStatus},
 };
 
-use clap::Parser;
+use clap::{
+    builder::{PossibleValuesParser, TypedValueParser},
+    Parser,
+};
 use color_eyre::{
     eyre::{bail, eyre, Context},
     Result,
 };
-use nyarr::tar::StripRoot;
+use nyarr::{
+    compression::{CompressedNarWriter, Compression},
+    diff::Difference,
+    hash::HashAlgo,
+    tar::{Streamed, StripRoot, Tar2NarOptions, UnsupportedEntries},
+};
 
 #[derive(Parser, Debug)]
 struct Tar2nar {
-    /// Tar file to convert
+    /// Tar file to convert, optionally compressed with gzip, xz, bzip2 or zstd
     tarfile: PathBuf,
     /// Output file
     narfile: PathBuf,
@@ -23,12 +32,82 @@ struct Tar2nar {
     no_verify: bool,
     /// Whether to strip the root directory out of the extraction
     strip_root: bool,
+    /// Verify against nyarr's own dump of the extracted files instead of
+    /// `nix-store --dump`, so Nix does not need to be installed
+    #[clap(long)]
+    pure_verify: bool,
+    /// Unpack like Nix's `fetchTarball`: the archive must contain exactly one
+    /// top-level file or directory, which becomes the root of the nar
+    #[clap(long, conflicts_with = "strip-root")]
+    single_root: bool,
+    /// What to do about device nodes, FIFOs and other entries that cannot be
+    /// put in a nar: fail the conversion, print a warning and leave them out,
+    /// or silently leave them out
+    #[clap(
+        long,
+        default_value_t,
+        value_parser = PossibleValuesParser::new(UnsupportedEntries::NAMES)
+            .map(|s| s.parse::<UnsupportedEntries>().unwrap()),
+    )]
+    unsupported_entries: UnsupportedEntries,
+    /// Compress the nar as binary caches do, and print the hashes and sizes a
+    /// narinfo needs
+    #[clap(long, value_enum, default_value_t = Compress::None)]
+    compress: Compress,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Compress {
+    None,
+    Xz,
+    Zstd,
+    Bzip2,
+    Gzip,
+}
+
+impl From<Compress> for Compression {
+    fn from(c: Compress) -> Self {
+        match c {
+            Compress::None => Compression::None,
+            Compress::Xz => Compression::Xz,
+            Compress::Zstd => Compression::Zstd,
+            Compress::Bzip2 => Compression::Bzip2,
+            Compress::Gzip => Compression::Gzip,
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+struct Nar2tar {
+    /// Nar file to convert
+    narfile: PathBuf,
+    /// Output file
+    tarfile: PathBuf,
+    /// Put everything in the tar under this directory name. Without it, the
+    /// nar has to be of a directory, whose contents go at the top level
+    #[clap(long)]
+    root: Option<OsString>,
+    /// Skip checking that the tar converts back into the same nar
+    #[clap(long)]
+    no_verify: bool,
+}
+
+#[derive(Parser, Debug)]
+struct Diff {
+    /// Nar file to compare from
+    a: PathBuf,
+    /// Nar file to compare to
+    b: PathBuf,
 }
 
 #[derive(Parser, Debug)]
 enum Subcommand {
     /// Convert a tar file to nar
     Tar2nar(Tar2nar),
+    /// Convert a nar file to a reproducible tar
+    Nar2tar(Nar2tar),
+    /// Show how the files in two nars differ
+    Diff(Diff),
 }
 #[derive(Parser, Debug)]
 struct Args {
@@ -64,54 +143,193 @@ fn extract_to_temp(file: &Path, strip_root: StripRoot) -> Result<tempfile::TempD
     Ok(temp)
 }
 
-fn nix_nar(file: &Path, strip_root: StripRoot) -> Result<Vec<u8>> {
+/// What to dump out of the extracted archive to get the root of the nar.
+fn dump_root(extracted: &Path, strip_root: StripRoot) -> Result<PathBuf> {
+    if strip_root != StripRoot::SingleRoot {
+        return Ok(extracted.to_owned());
+    }
+    let mut entries = std::fs::read_dir(extracted)?.collect::<Result<Vec<_>, _>>()?;
+    match entries.pop() {
+        Some(entry) if entries.is_empty() => Ok(entry.path()),
+        _ => bail!("extracted archive does not have exactly one top-level entry"),
+    }
+}
+
+/// Dumps the extracted archive into `into` with `nix-store --dump`.
+fn nix_nar(file: &Path, strip_root: StripRoot, into: &File) -> Result<()> {
     let extracted = extract_to_temp(file, strip_root)?;
-    let out = Command::new("nix-store")
-        .args([&OsString::from("--dump"), extracted.path().as_os_str()])
-        .output()?;
-    check_status(out.status)?;
-    Ok(out.stdout)
+    let root = dump_root(extracted.path(), strip_root)?;
+    check_status(
+        Command::new("nix-store")
+            .args([OsStr::new("--dump"), root.as_os_str()])
+            .stdout(into.try_clone()?)
+            .status()?,
+    )
+}
+
+fn nyarr_nar(file: &Path, strip_root: StripRoot, into: &File) -> Result<()> {
+    let extracted = extract_to_temp(file, strip_root)?;
+    let root = dump_root(extracted.path(), strip_root)?;
+    let mut out = BufWriter::new(into);
+    nyarr::fs::path_to_nar(&root, &mut out).context("dumping extracted files")?;
+    out.flush()?;
+    Ok(())
+}
+
+fn nar_differences(a: impl Read, b: impl Read) -> Result<Vec<Difference>> {
+    let a = nyarr::nar::nar_to_fsobject(a).context("parsing first nar")?;
+    let b = nyarr::nar::nar_to_fsobject(b).context("parsing second nar")?;
+    Ok(nyarr::diff::diff(&a, &b)?)
+}
+
+/// Reads back the nar written to `narfile` to see how it differs from
+/// `reference`.
+fn output_differences(mut reference: &File, narfile: &Path) -> Result<Vec<Difference>> {
+    reference.rewind()?;
+    let (_, ours) = nyarr::compression::decompress(BufReader::new(
+        File::open(narfile).context("reading back output nar file")?,
+    ))?;
+    nar_differences(BufReader::new(reference), ours)
 }
 
 fn tar2nar(args: Tar2nar) -> Result<()> {
-    let strip_root = if args.strip_root {
+    let strip_root = if args.single_root {
+        StripRoot::SingleRoot
+    } else if args.strip_root {
         StripRoot::StripRoot
     } else {
         StripRoot::DontStripRoot
     };
 
-    let mut reader = BufReader::new(File::open(&args.tarfile).context("opening tar file")?);
-    let mut writer = BufWriter::new(
-        OpenOptions::new()
+    let open_tar = || -> Result<_> {
+        let (_, decoder) = nyarr::compression::decompress(BufReader::new(
+            File::open(&args.tarfile).context("opening tar file")?,
+        ))
+        .context("decompressing tar file")?;
+        Ok(decoder)
+    };
+    let create_output = || -> Result<_> {
+        let file = OpenOptions::new()
             .write(true)
             .truncate(true)
             .create(true)
-            .open(args.narfile)
-            .context("opening output nar file")?,
-    );
-    let mut out = Vec::new();
+            .open(&args.narfile)
+            .context("opening output nar file")?;
+        Ok(CompressedNarWriter::new(
+            args.compress.into(),
+            BufWriter::new(file),
+        )?)
+    };
 
-    nyarr::tar::tar_to_nar(&mut reader, &mut out, strip_root)
+    let options = Tar2NarOptions {
+        strip_root,
+        strictness: args
+            .unsupported_entries
+            .strictness(|e| eprintln!("warning: skipping {e}")),
+    };
+    let mut out = create_output()?;
+    let streamed = nyarr::tar::stream_tar_to_nar(open_tar()?, &mut out, &options)
         .map_err(|e| eyre!(e))
         .context("error converting from tar to nar")?;
+    if let Streamed::NotStreamable { .. } = streamed {
+        // flush the abandoned attempt before the file is truncated, not after
+        drop(out);
+
+        out = create_output()?;
+        nyarr::tar::spooled_tar_to_nar(open_tar()?, &mut out, &options)
+            .map_err(|e| eyre!(e))
+            .context("error converting from tar to nar")?;
+    }
+
+    let (mut writer, info) = out.finish().context("compressing nar")?;
+    writer.flush()?;
+    if args.compress != Compress::None {
+        println!("NarHash: {}", info.nar_hash.to_typed_base32());
+        println!("NarSize: {}", info.nar_size);
+        println!("FileHash: {}", info.file_hash.to_typed_base32());
+        println!("FileSize: {}", info.file_size);
+    }
+
+    if !args.no_verify {
+        let mut reference = tempfile::tempfile().context("creating temporary file")?;
+        let reproduce = if args.pure_verify {
+            nyarr_nar(&args.tarfile, strip_root, &reference)?;
+            "dumping EXTRACTED_DIR with `nyarr::fs::path_to_nar`"
+        } else {
+            nix_nar(&args.tarfile, strip_root, &reference)?;
+            "`nix-store --dump EXTRACTED_DIR`"
+        };
+        reference.rewind()?;
+        let expected = nyarr::hash::flat_hash(BufReader::new(&reference), HashAlgo::Sha256)?;
+        if info.nar_hash != expected {
+            // the mismatch is the error, whether or not this can explain it
+            match output_differences(&reference, &args.narfile) {
+                Ok(differences) => {
+                    for difference in differences {
+                        eprintln!("{difference}");
+                    }
+                }
+                Err(e) => eprintln!("warning: could not compare the nars: {e:#}"),
+            }
+            bail!("Mismatched NAR results! This is a bug. Reproduce with {reproduce}.");
+        }
+    }
 
-    writer.write_all(&out)?;
+    Ok(())
+}
+
+fn nar2tar(args: Nar2tar) -> Result<()> {
+    let nar = std::fs::read(&args.narfile).context("reading nar file")?;
+    let fso = nyarr::nar::nar_to_fsobject(&nar[..]).context("parsing nar file")?;
+    let root = args.root.as_ref().map(|r| r.as_bytes());
+
+    let mut tar = Vec::new();
+    nyarr::tar::fsobject_to_tar(&fso, root, &mut tar)
+        .map_err(|e| eyre!(e))
+        .context("error converting from nar to tar")?;
+    std::fs::write(&args.tarfile, &tar).context("writing output tar file")?;
 
     if !args.no_verify {
-        let nix_result = nix_nar(&args.tarfile, strip_root)?;
-        if &out != &nix_result {
-            bail!("Mismatched NAR results! This is a bug. Reproduce with `nix-store --dump EXTRACTED_DIR`.");
+        let options = Tar2NarOptions {
+            strip_root: if root.is_some() {
+                StripRoot::SingleRoot
+            } else {
+                StripRoot::DontStripRoot
+            },
+            ..Default::default()
+        };
+        let mut again = Vec::new();
+        nyarr::tar::tar_to_nar(Cursor::new(&tar), &mut again, &options)
+            .map_err(|e| eyre!(e))
+            .context("converting the tar back to nar")?;
+        if again != nar {
+            bail!("The tar does not convert back into the same nar! This is a bug.");
         }
     }
 
     Ok(())
 }
 
+fn diff(args: Diff) -> Result<()> {
+    let a = File::open(&args.a).context("opening first nar file")?;
+    let b = File::open(&args.b).context("opening second nar file")?;
+    let differences = nar_differences(BufReader::new(a), BufReader::new(b))?;
+    for difference in &differences {
+        println!("{difference}");
+    }
+    if !differences.is_empty() {
+        bail!("The nars differ");
+    }
+    Ok(())
+}
+
 fn main() -> Result<()> {
     color_eyre::install()?;
     let args = Args::parse();
     match args.subcommand {
         Subcommand::Tar2nar(t2n) => tar2nar(t2n),
+        Subcommand::Nar2tar(n2t) => nar2tar(n2t),
+        Subcommand::Diff(d) => diff(d),
     }?;
     Ok(())
 }
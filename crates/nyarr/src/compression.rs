@@ -0,0 +1,372 @@
+//! Detecting and undoing the compression that archives usually come wrapped
+//! in.
+
+use std::io::{self, Cursor, Read, Write};
+
+use crate::{
+    hash::{Hash, NarHasher},
+    ByteStream, FsObject,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Bzip2,
+    Zstd,
+}
+
+/// Longest magic number we look for.
+const MAGIC_LEN: usize = 10;
+
+/// bzip2 streams start with `BZh`, the block size, then either a block or the
+/// end of the stream. `BZh` alone is too likely to be the start of a file
+/// name in a plain tar.
+fn is_bzip2(header: &[u8]) -> bool {
+    const BLOCK: [u8; 6] = [0x31, 0x41, 0x59, 0x26, 0x53, 0x59];
+    const END: [u8; 6] = [0x17, 0x72, 0x45, 0x38, 0x50, 0x90];
+    match header {
+        [b'B', b'Z', b'h', b'1'..=b'9', magic @ ..] => {
+            magic.starts_with(&BLOCK) || magic.starts_with(&END)
+        }
+        _ => false,
+    }
+}
+
+impl Compression {
+    /// Guesses the compression of some data from its first few bytes. Anything
+    /// unrecognised is assumed to be uncompressed.
+    pub fn detect(header: &[u8]) -> Compression {
+        if header.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if header.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Compression::Xz
+        } else if is_bzip2(header) {
+            Compression::Bzip2
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// What narinfo files call this compression.
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Xz => "xz",
+            Compression::Bzip2 => "bzip2",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Compression> {
+        [
+            Compression::None,
+            Compression::Gzip,
+            Compression::Xz,
+            Compression::Bzip2,
+            Compression::Zstd,
+        ]
+        .into_iter()
+        .find(|c| c.name() == name)
+    }
+
+    /// Wraps `r` in the matching decoder.
+    pub fn decoder<'a>(self, r: impl Read + 'a) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Compression::None => Box::new(r),
+            Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(r)),
+            Compression::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(r)),
+            Compression::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(r)),
+            Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(r)?),
+        })
+    }
+}
+
+/// Sniffs the compression of `r` and returns a reader of its decompressed
+/// contents.
+pub fn decompress<'a>(mut r: impl Read + 'a) -> io::Result<(Compression, Box<dyn Read + 'a>)> {
+    let mut header = [0u8; MAGIC_LEN];
+    let mut got = 0;
+    while got < MAGIC_LEN {
+        match r.read(&mut header[got..]) {
+            Ok(0) => break,
+            Ok(n) => got += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    let compression = Compression::detect(&header[..got]);
+    let rest = Cursor::new(header).take(got as u64).chain(r);
+    Ok((compression, compression.decoder(rest)?))
+}
+
+/// Decompresses all of `r` into memory, for use with the archive readers that
+/// need [`std::io::Seek`].
+pub fn decompress_to_vec(r: impl Read) -> io::Result<Vec<u8>> {
+    let (_, mut decoder) = decompress(r)?;
+    let mut out = Vec::new();
+    decoder.read_to_end(&mut out)?;
+    Ok(out)
+}
+
+/// A compressor for one of the [`Compression`]s.
+enum Encoder<W: Write> {
+    None(W),
+    Gzip(flate2::write::GzEncoder<W>),
+    Xz(xz2::write::XzEncoder<W>),
+    Bzip2(bzip2::write::BzEncoder<W>),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Uses the levels Nix does by default.
+    fn new(compression: Compression, w: W) -> io::Result<Encoder<W>> {
+        Ok(match compression {
+            Compression::None => Encoder::None(w),
+            Compression::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                w,
+                flate2::Compression::default(),
+            )),
+            Compression::Xz => Encoder::Xz(xz2::write::XzEncoder::new(w, 6)),
+            Compression::Bzip2 => {
+                Encoder::Bzip2(bzip2::write::BzEncoder::new(w, bzip2::Compression::best()))
+            }
+            Compression::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(w, 3)?),
+        })
+    }
+
+    fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::None(w) => Ok(w),
+            Encoder::Gzip(e) => e.finish(),
+            Encoder::Xz(e) => e.finish(),
+            Encoder::Bzip2(e) => e.finish(),
+            Encoder::Zstd(e) => e.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::None(w) => w.write(buf),
+            Encoder::Gzip(e) => e.write(buf),
+            Encoder::Xz(e) => e.write(buf),
+            Encoder::Bzip2(e) => e.write(buf),
+            Encoder::Zstd(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::None(w) => w.flush(),
+            Encoder::Gzip(e) => e.flush(),
+            Encoder::Xz(e) => e.flush(),
+            Encoder::Bzip2(e) => e.flush(),
+            Encoder::Zstd(e) => e.flush(),
+        }
+    }
+}
+
+/// Hashes and counts what passes through it.
+struct Measured<W> {
+    inner: W,
+    hasher: NarHasher,
+    size: u64,
+}
+
+impl<W> Measured<W> {
+    fn new(inner: W) -> Measured<W> {
+        Measured {
+            inner,
+            hasher: NarHasher::new(),
+            size: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for Measured<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.write_all(&buf[..n])?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The sizes and sha256 hashes a binary cache records about a compressed nar.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NarFileInfo {
+    pub nar_hash: Hash,
+    pub nar_size: u64,
+    /// Of the nar as compressed.
+    pub file_hash: Hash,
+    pub file_size: u64,
+}
+
+/// Compresses a nar written into it on its way to `W`, measuring both sides
+/// as it goes so neither has to be read again.
+pub struct CompressedNarWriter<W: Write>(Measured<Encoder<Measured<W>>>);
+
+impl<W: Write> CompressedNarWriter<W> {
+    pub fn new(compression: Compression, w: W) -> io::Result<CompressedNarWriter<W>> {
+        Ok(CompressedNarWriter(Measured::new(Encoder::new(
+            compression,
+            Measured::new(w),
+        )?)))
+    }
+
+    /// Flushes out the rest of the compressed data.
+    pub fn finish(self) -> io::Result<(W, NarFileInfo)> {
+        let Measured {
+            inner: encoder,
+            hasher: nar_hasher,
+            size: nar_size,
+        } = self.0;
+        let file = encoder.finish()?;
+        Ok((
+            file.inner,
+            NarFileInfo {
+                nar_hash: nar_hasher.finish(),
+                nar_size,
+                file_hash: file.hasher.finish(),
+                file_size: file.size,
+            },
+        ))
+    }
+}
+
+impl<W: Write> Write for CompressedNarWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Serialises `fso` into `w` as a nar compressed with `compression`.
+pub fn write_compressed_nar<T: ByteStream, W: Write>(
+    fso: &FsObject<T>,
+    compression: Compression,
+    w: W,
+) -> io::Result<(W, NarFileInfo)> {
+    let mut writer = CompressedNarWriter::new(compression, w)?;
+    fso.serialise_toplevel(&mut writer)?;
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::{flat_hash, HashAlgo};
+
+    const TAR: &[u8] = include_bytes!("testdata/test1.tar");
+
+    fn compressed(compression: Compression) -> Vec<u8> {
+        match compression {
+            Compression::None => TAR.to_vec(),
+            Compression::Gzip => {
+                let mut e =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                e.write_all(TAR).unwrap();
+                e.finish().unwrap()
+            }
+            Compression::Xz => {
+                let mut e = xz2::write::XzEncoder::new(Vec::new(), 6);
+                e.write_all(TAR).unwrap();
+                e.finish().unwrap()
+            }
+            Compression::Bzip2 => {
+                let mut e = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::best());
+                e.write_all(TAR).unwrap();
+                e.finish().unwrap()
+            }
+            Compression::Zstd => zstd::encode_all(TAR, 3).unwrap(),
+        }
+    }
+
+    #[test]
+    fn detects_and_decompresses() {
+        for compression in [
+            Compression::None,
+            Compression::Gzip,
+            Compression::Xz,
+            Compression::Bzip2,
+            Compression::Zstd,
+        ] {
+            let data = compressed(compression);
+            let (detected, mut decoder) = decompress(&data[..]).unwrap();
+            assert_eq!(detected, compression);
+
+            let mut out = Vec::new();
+            decoder.read_to_end(&mut out).unwrap();
+            assert_eq!(out, TAR, "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn compressed_nar() {
+        let nar = include_bytes!("testdata/test2.nar");
+        let fso = crate::nar::nar_to_fsobject(&nar[..]).unwrap();
+        for compression in [
+            Compression::None,
+            Compression::Gzip,
+            Compression::Xz,
+            Compression::Bzip2,
+            Compression::Zstd,
+        ] {
+            let (file, info) = write_compressed_nar(&fso, compression, Vec::new()).unwrap();
+            assert_eq!(Compression::detect(&file), compression);
+            assert_eq!(decompress_to_vec(&file[..]).unwrap(), nar);
+            assert_eq!(
+                info,
+                NarFileInfo {
+                    nar_hash: flat_hash(&nar[..], HashAlgo::Sha256).unwrap(),
+                    nar_size: nar.len() as u64,
+                    file_hash: flat_hash(&file[..], HashAlgo::Sha256).unwrap(),
+                    file_size: file.len() as u64,
+                },
+                "{compression:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn tar_named_like_bzip2() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(0o755);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "BZhello/", &b""[..])
+            .unwrap();
+        let tar = builder.into_inner().unwrap();
+        assert_eq!(Compression::detect(&tar), Compression::None);
+        assert_eq!(decompress_to_vec(&tar[..]).unwrap(), tar);
+
+        let mut empty = Vec::new();
+        bzip2::write::BzEncoder::new(&mut empty, bzip2::Compression::best())
+            .finish()
+            .unwrap();
+        assert_eq!(Compression::detect(&empty), Compression::Bzip2);
+    }
+
+    #[test]
+    fn short_input() {
+        assert_eq!(decompress_to_vec(&b"ab"[..]).unwrap(), b"ab");
+        assert_eq!(decompress_to_vec(&b""[..]).unwrap(), b"");
+    }
+}
@@ -0,0 +1,286 @@
+//! Finding out how two trees differ, for when two nars that should be the
+//! same are not.
+
+use std::{collections::BTreeSet, fmt, io};
+
+use crate::{
+    hash::{Hash, NarHasher},
+    ByteStream, Executable, FsObject, SymlinkTarget,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Directory,
+    Symlink,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Kind::File => "file",
+            Kind::Directory => "directory",
+            Kind::Symlink => "symlink",
+        })
+    }
+}
+
+fn kind<T: ByteStream>(obj: &FsObject<T>) -> Kind {
+    match obj {
+        FsObject::File(..) => Kind::File,
+        FsObject::Directory(_) => Kind::Directory,
+        FsObject::Symlink(_) => Kind::Symlink,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// Only in the second tree. Nothing is reported inside added directories.
+    Added(Kind),
+    /// Only in the first tree.
+    Removed(Kind),
+    TypeChanged {
+        from: Kind,
+        to: Kind,
+    },
+    ExecutableChanged {
+        from: Executable,
+        to: Executable,
+    },
+    ContentsChanged,
+    TargetChanged {
+        from: SymlinkTarget,
+        to: SymlinkTarget,
+    },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added(kind) => write!(f, "added {kind}"),
+            Change::Removed(kind) => write!(f, "removed {kind}"),
+            Change::TypeChanged { from, to } => write!(f, "changed from {from} to {to}"),
+            Change::ExecutableChanged {
+                to: Executable::IsExecutable,
+                ..
+            } => f.write_str("became executable"),
+            Change::ExecutableChanged { .. } => f.write_str("is no longer executable"),
+            Change::ContentsChanged => f.write_str("contents changed"),
+            Change::TargetChanged { from, to } => write!(
+                f,
+                "symlink target changed from {:?} to {:?}",
+                String::from_utf8_lossy(from.as_bytes()),
+                String::from_utf8_lossy(to.as_bytes())
+            ),
+        }
+    }
+}
+
+/// A [`Change`] at a path, which is empty for the root.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Difference {
+    pub path: Vec<u8>,
+    pub change: Change,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, ".: {}", self.change)
+        } else {
+            write!(
+                f,
+                "{}: {}",
+                String::from_utf8_lossy(&self.path),
+                self.change
+            )
+        }
+    }
+}
+
+fn contents_hash(contents: &impl ByteStream) -> io::Result<Hash> {
+    let mut hasher = NarHasher::new();
+    contents.write_into(&mut hasher)?;
+    Ok(hasher.finish())
+}
+
+fn join(path: &[u8], name: &[u8]) -> Vec<u8> {
+    if path.is_empty() {
+        name.to_vec()
+    } else {
+        [path, b"/", name].concat()
+    }
+}
+
+fn diff_into<T: ByteStream, U: ByteStream>(
+    path: Vec<u8>,
+    a: &FsObject<T>,
+    b: &FsObject<U>,
+    out: &mut Vec<Difference>,
+) -> io::Result<()> {
+    let mut push = |change| {
+        out.push(Difference {
+            path: path.clone(),
+            change,
+        })
+    };
+
+    match (a, b) {
+        (FsObject::File(exec_a, contents_a), FsObject::File(exec_b, contents_b)) => {
+            if exec_a != exec_b {
+                push(Change::ExecutableChanged {
+                    from: *exec_a,
+                    to: *exec_b,
+                });
+            }
+            // hashing keeps lazily read contents out of memory
+            if contents_a.len() != contents_b.len()
+                || contents_hash(contents_a)?.bytes != contents_hash(contents_b)?.bytes
+            {
+                push(Change::ContentsChanged);
+            }
+        }
+        (FsObject::Symlink(from), FsObject::Symlink(to)) => {
+            if from != to {
+                push(Change::TargetChanged {
+                    from: from.clone(),
+                    to: to.clone(),
+                });
+            }
+        }
+        (FsObject::Directory(dir_a), FsObject::Directory(dir_b)) => {
+            let names = dir_a
+                .0
+                .keys()
+                .chain(dir_b.0.keys())
+                .collect::<BTreeSet<_>>();
+            for name in names {
+                let child = join(&path, name);
+                match (dir_a.0.get(name), dir_b.0.get(name)) {
+                    (Some(a), Some(b)) => diff_into(child, a, b, out)?,
+                    (Some(a), None) => out.push(Difference {
+                        path: child,
+                        change: Change::Removed(kind(a)),
+                    }),
+                    (None, Some(b)) => out.push(Difference {
+                        path: child,
+                        change: Change::Added(kind(b)),
+                    }),
+                    (None, None) => unreachable!("name came from one of them"),
+                }
+            }
+        }
+        _ => push(Change::TypeChanged {
+            from: kind(a),
+            to: kind(b),
+        }),
+    }
+    Ok(())
+}
+
+/// Lists everything that differs between `a` and `b`, in nar order. An empty
+/// list means both serialise to the same nar.
+pub fn diff<T: ByteStream, U: ByteStream>(
+    a: &FsObject<T>,
+    b: &FsObject<U>,
+) -> io::Result<Vec<Difference>> {
+    let mut out = Vec::new();
+    diff_into(Vec::new(), a, b, &mut out)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{nar::nar_to_fsobject, ConstByteStream, Directory, FileName};
+
+    fn tree(items: &[(&str, FsObject<ConstByteStream>)]) -> FsObject<ConstByteStream> {
+        let mut dir = Directory::default();
+        for (path, obj) in items {
+            let obj = match obj {
+                FsObject::File(exec, contents) => FsObject::File(*exec, contents.clone()),
+                FsObject::Symlink(to) => FsObject::Symlink(to.clone()),
+                FsObject::Directory(_) => FsObject::Directory(Directory::default()),
+            };
+            dir.insert(&FileName::try_from(path.as_bytes()).unwrap(), obj)
+                .unwrap();
+        }
+        FsObject::Directory(dir)
+    }
+
+    fn file(exec: Executable, s: &str) -> FsObject<ConstByteStream> {
+        FsObject::File(exec, ConstByteStream(s.as_bytes().to_vec()))
+    }
+
+    fn link(s: &str) -> FsObject<ConstByteStream> {
+        FsObject::Symlink(SymlinkTarget::from(s.as_bytes()))
+    }
+
+    #[test]
+    fn same() {
+        let nar = include_bytes!("testdata/test2.nar");
+        let a = nar_to_fsobject(&nar[..]).unwrap();
+        let b = nar_to_fsobject(&nar[..]).unwrap();
+        assert_eq!(diff(&a, &b).unwrap(), []);
+    }
+
+    #[test]
+    fn changes() {
+        use Executable::*;
+        let a = tree(&[
+            ("exec", file(IsExecutable, "a")),
+            ("gone/x", file(NotExecutable, "x")),
+            ("contents", file(NotExecutable, "a")),
+            ("link", link("/a")),
+            ("sub/type", file(NotExecutable, "a")),
+        ]);
+        let b = tree(&[
+            ("exec", file(NotExecutable, "a")),
+            ("contents", file(NotExecutable, "b")),
+            ("link", link("a")),
+            ("new/y", file(NotExecutable, "y")),
+            ("sub/type", link("a")),
+        ]);
+
+        let found = diff(&a, &b).unwrap();
+        let expected = [
+            ("contents", Change::ContentsChanged),
+            (
+                "exec",
+                Change::ExecutableChanged {
+                    from: IsExecutable,
+                    to: NotExecutable,
+                },
+            ),
+            ("gone", Change::Removed(Kind::Directory)),
+            (
+                "link",
+                Change::TargetChanged {
+                    from: SymlinkTarget::from(&b"/a"[..]),
+                    to: SymlinkTarget::from(&b"a"[..]),
+                },
+            ),
+            ("new", Change::Added(Kind::Directory)),
+            (
+                "sub/type",
+                Change::TypeChanged {
+                    from: Kind::File,
+                    to: Kind::Symlink,
+                },
+            ),
+        ]
+        .map(|(path, change)| Difference {
+            path: path.as_bytes().to_vec(),
+            change,
+        });
+        assert_eq!(found, expected);
+        assert_eq!(found[0].to_string(), "contents: contents changed");
+        assert_eq!(
+            found[3].to_string(),
+            r#"link: symlink target changed from "/a" to "a""#
+        );
+
+        let root = diff(&file(NotExecutable, "a"), &a).unwrap();
+        assert_eq!(root[0].to_string(), ".: changed from file to directory");
+    }
+}
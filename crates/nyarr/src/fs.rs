@@ -0,0 +1,386 @@
+//! Making a nar from a directory on disk, like `nix-store --dump`, and putting
+//! one back onto disk, like `nix-store --restore`.
+
+use std::{
+    ffi::OsStr,
+    fs::{self, File, OpenOptions, Permissions},
+    io::{self, Read, Write},
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    io_error,
+    nar::{valid_name, EntryKind, NarStreamReader},
+    unk_error, ByteStream, Directory, Executable, FsObject, SymlinkTarget, Tar2NarError,
+    WriteResult,
+};
+
+/// [`ByteStream`] backed by a file on disk, which is only opened and read
+/// when it is serialised.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileByteStream {
+    path: PathBuf,
+    len: usize,
+}
+
+impl ByteStream for FileByteStream {
+    fn write_into(&self, w: &mut dyn Write) -> WriteResult {
+        let mut f = File::open(&self.path)?;
+        let copied = io::copy(&mut (&mut f).take(self.len as u64), w)?;
+        if copied != self.len as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} shrank while it was being read", self.path.display()),
+            ));
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+/// Builds an [`FsObject`] out of whatever is at `path`, without following
+/// symlinks, the same way `nix-store --dump` does.
+///
+/// File contents are not read until the object is serialised, so the files
+/// must not be modified in between.
+pub fn path_to_fsobject(path: &Path) -> Result<FsObject<FileByteStream>, Tar2NarError> {
+    let meta = fs::symlink_metadata(path).map_err(io_error)?;
+    let file_type = meta.file_type();
+
+    if file_type.is_dir() {
+        let mut dir = Directory::default();
+        for entry in fs::read_dir(path).map_err(io_error)? {
+            let entry = entry.map_err(io_error)?;
+            let child = path_to_fsobject(&entry.path())?;
+            dir.0
+                .insert(entry.file_name().as_bytes().to_vec(), Box::new(child));
+        }
+        Ok(FsObject::Directory(dir))
+    } else if file_type.is_file() {
+        Ok(FsObject::File(
+            Executable::from_mode(meta.permissions().mode()),
+            FileByteStream {
+                path: path.to_owned(),
+                len: meta.len() as usize,
+            },
+        ))
+    } else if file_type.is_symlink() {
+        let target = fs::read_link(path).map_err(io_error)?;
+        Ok(FsObject::Symlink(SymlinkTarget::from(
+            target.as_os_str().as_bytes(),
+        )))
+    } else {
+        Err(unk_error(format!(
+            "{} has an unsupported file type",
+            path.display()
+        )))
+    }
+}
+
+/// Equivalent to `nix-store --dump`.
+pub fn path_to_nar(path: &Path, mut into: impl Write) -> Result<(), Tar2NarError> {
+    let fso = path_to_fsobject(path)?;
+    fso.serialise_toplevel(&mut into).map_err(io_error)
+}
+
+/// Permissions restored files get, in the style of the Nix store.
+fn file_permissions(exec: Executable) -> Permissions {
+    Permissions::from_mode(match exec {
+        Executable::IsExecutable => 0o555,
+        Executable::NotExecutable => 0o444,
+    })
+}
+
+fn create_file(path: &Path, exec: Executable, created: &mut bool) -> Result<File, Tar2NarError> {
+    let f = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(io_error)?;
+    *created = true;
+    f.set_permissions(file_permissions(exec))
+        .map_err(io_error)?;
+    Ok(f)
+}
+
+fn check_target(target: &Path) -> Result<(), Tar2NarError> {
+    if target.symlink_metadata().is_ok() {
+        return Err(Tar2NarError::TargetExists(target.to_owned()));
+    }
+    Ok(())
+}
+
+/// Checks every name in `obj` before anything is written, so that a rejected
+/// tree leaves nothing behind.
+fn check_names<T: ByteStream>(obj: &FsObject<T>, target: &Path) -> Result<(), Tar2NarError> {
+    if let FsObject::Directory(entries) = obj {
+        for (name, child) in &entries.0 {
+            if !valid_name(name) {
+                return Err(Tar2NarError::InvalidName {
+                    name: String::from_utf8_lossy(name).into_owned(),
+                    dir: target.to_owned(),
+                });
+            }
+            check_names(child, &target.join(OsStr::from_bytes(name)))?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs `restore`, removing whatever it wrote to `target` if it fails part
+/// way.
+///
+/// `restore` sets its flag once it has created something itself. Until then
+/// nothing is removed: `target` may have been made by someone else since
+/// [`check_target`], and failing to create it is not a reason to delete it.
+fn remove_on_error(
+    target: &Path,
+    restore: impl FnOnce(&mut bool) -> Result<(), Tar2NarError>,
+) -> Result<(), Tar2NarError> {
+    let mut created = false;
+    let result = restore(&mut created);
+    if result.is_err() && created {
+        // directories are left writable, so everything in them can go
+        let _ = match target.symlink_metadata() {
+            Ok(meta) if meta.is_dir() => fs::remove_dir_all(target),
+            Ok(_) => fs::remove_file(target),
+            Err(_) => Ok(()),
+        };
+    }
+    result
+}
+
+fn restore_one<T: ByteStream>(
+    obj: &FsObject<T>,
+    target: &Path,
+    created: &mut bool,
+) -> Result<(), Tar2NarError> {
+    match obj {
+        FsObject::File(exec, content) => {
+            let mut f = create_file(target, *exec, created)?;
+            content.write_into(&mut f).map_err(io_error)?;
+        }
+        FsObject::Directory(entries) => {
+            fs::create_dir(target).map_err(io_error)?;
+            *created = true;
+            for (name, child) in &entries.0 {
+                restore_one(child, &target.join(OsStr::from_bytes(name)), created)?;
+            }
+        }
+        FsObject::Symlink(to) => {
+            std::os::unix::fs::symlink(OsStr::from_bytes(to.as_bytes()), target)
+                .map_err(io_error)?;
+            *created = true;
+        }
+    }
+    Ok(())
+}
+
+/// Writes `obj` out to `target`, which must not exist yet.
+///
+/// Files are made read only, as they would be in the Nix store, but
+/// directories are left writable so the result can be deleted again. Nothing
+/// is left at `target` if restoring fails.
+pub fn restore_fsobject<T: ByteStream>(
+    obj: &FsObject<T>,
+    target: &Path,
+) -> Result<(), Tar2NarError> {
+    check_target(target)?;
+    check_names(obj, target)?;
+    remove_on_error(target, |created| restore_one(obj, target, created))
+}
+
+/// Equivalent to `nix-store --restore`, with the same permissions as
+/// [`restore_fsobject`].
+///
+/// The nar is streamed onto disk, so this does not need to hold the archive in
+/// memory. If it turns out to be invalid part way, what was already written is
+/// removed again.
+pub fn restore_nar(nar: impl Read, target: &Path) -> Result<(), Tar2NarError> {
+    check_target(target)?;
+    remove_on_error(target, |created| restore_nar_entries(nar, target, created))
+}
+
+fn restore_nar_entries(
+    nar: impl Read,
+    target: &Path,
+    created: &mut bool,
+) -> Result<(), Tar2NarError> {
+    let mut reader = NarStreamReader::new(nar);
+    while let Some(mut entry) = reader.next_entry()? {
+        // names are validated by the reader, so this cannot escape target
+        let path = match &entry.path {
+            None => target.to_owned(),
+            Some(path) => target.join(OsStr::from_bytes(&path.to_path())),
+        };
+
+        match entry.kind {
+            EntryKind::Regular => {
+                let mut f = create_file(&path, entry.executable, created)?;
+                io::copy(&mut entry.contents, &mut f).map_err(io_error)?;
+            }
+            EntryKind::Directory => {
+                fs::create_dir(&path).map_err(io_error)?;
+                *created = true;
+            }
+            EntryKind::Symlink(to) => {
+                std::os::unix::fs::symlink(OsStr::from_bytes(to.as_bytes()), &path)
+                    .map_err(io_error)?;
+                *created = true;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hash::NarHasher, nar::NarError, ConstByteStream};
+
+    fn test2_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("test2")
+    }
+
+    #[test]
+    fn matches_nix_nar() {
+        let mut nar = Vec::new();
+        path_to_nar(&test2_dir(), &mut nar).unwrap();
+        assert_eq!(nar, include_bytes!("testdata/test2.nar"));
+    }
+
+    #[test]
+    fn hash_without_buffering() {
+        let mut hasher = NarHasher::new();
+        path_to_nar(&test2_dir(), &mut hasher).unwrap();
+
+        let mut expected = NarHasher::new();
+        expected
+            .write_all(include_bytes!("testdata/test2.nar"))
+            .unwrap();
+        assert_eq!(hasher.digest(), expected.digest());
+    }
+
+    #[test]
+    fn restore_round_trip() {
+        let temp = tempfile::tempdir().unwrap();
+        let out = temp.path().join("out");
+        let nar = include_bytes!("testdata/test2.nar");
+        restore_nar(&nar[..], &out).unwrap();
+
+        let mode = |p: &str| fs::metadata(out.join(p)).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode("exe"), 0o555);
+        assert_eq!(mode("f"), 0o444);
+        assert_eq!(fs::read_link(out.join("f2")).unwrap(), Path::new("f"));
+
+        let mut dumped = Vec::new();
+        path_to_nar(&out, &mut dumped).unwrap();
+        assert_eq!(dumped, nar);
+
+        let out2 = temp.path().join("out2");
+        let fso = crate::nar::nar_to_fsobject(&nar[..]).unwrap();
+        restore_fsobject(&fso, &out2).unwrap();
+        let mut dumped = Vec::new();
+        path_to_nar(&out2, &mut dumped).unwrap();
+        assert_eq!(dumped, nar);
+    }
+
+    #[test]
+    fn raw_symlink_targets() {
+        let temp = tempfile::tempdir().unwrap();
+        let out = temp.path().join("out");
+        let nar = include_bytes!("testdata/links.nar");
+        restore_nar(&nar[..], &out).unwrap();
+        assert_eq!(
+            fs::read_link(out.join("abs")).unwrap(),
+            Path::new("/usr/bin/env")
+        );
+
+        let mut dumped = Vec::new();
+        path_to_nar(&out, &mut dumped).unwrap();
+        assert_eq!(dumped, nar);
+    }
+
+    #[test]
+    fn restore_refuses_bad_targets() {
+        let temp = tempfile::tempdir().unwrap();
+        let nar = include_bytes!("testdata/test2.nar");
+        assert!(matches!(
+            restore_nar(&nar[..], temp.path()),
+            Err(Tar2NarError::TargetExists(p)) if p == temp.path()
+        ));
+
+        let mut evil = Directory::default();
+        evil.0.insert(
+            b"..".to_vec(),
+            Box::new(FsObject::File(
+                Executable::NotExecutable,
+                ConstByteStream(b"pwned".to_vec()),
+            )),
+        );
+        let out = temp.path().join("out");
+        assert!(matches!(
+            restore_fsobject(&FsObject::Directory(evil), &out),
+            Err(Tar2NarError::InvalidName { name, .. }) if name == ".."
+        ));
+        assert!(!temp.path().join("pwned").exists());
+        assert!(!out.exists());
+    }
+
+    #[test]
+    fn failed_restores_leave_nothing() {
+        let temp = tempfile::tempdir().unwrap();
+        let out = temp.path().join("out");
+
+        // "a" sorts before the bad name, so would be written first
+        let mut sub = Directory::default();
+        sub.0.insert(
+            b"../x".to_vec(),
+            Box::new(FsObject::Symlink(b"x"[..].into())),
+        );
+        let mut dir = Directory::default();
+        dir.0.insert(
+            b"a".to_vec(),
+            Box::new(FsObject::File(
+                Executable::NotExecutable,
+                ConstByteStream(b"a".to_vec()),
+            )),
+        );
+        dir.0
+            .insert(b"b".to_vec(), Box::new(FsObject::Directory(sub)));
+        assert!(matches!(
+            restore_fsobject(&FsObject::Directory(dir), &out),
+            Err(Tar2NarError::InvalidName { .. })
+        ));
+        assert!(!out.exists());
+
+        let nar = include_bytes!("testdata/test2.nar");
+        assert!(matches!(
+            restore_nar(&nar[..nar.len() - 8], &out),
+            Err(Tar2NarError::Nar(NarError::UnexpectedEof(_)))
+        ));
+        assert!(!out.exists());
+    }
+
+    #[test]
+    fn failed_restores_keep_others_targets() {
+        // as if someone else made `out` after check_target had passed
+        let temp = tempfile::tempdir().unwrap();
+        let out = temp.path().join("out");
+        fs::create_dir(&out).unwrap();
+        fs::write(out.join("theirs"), b"theirs").unwrap();
+
+        let nar = include_bytes!("testdata/test2.nar");
+        assert!(remove_on_error(&out, |created| {
+            restore_nar_entries(&nar[..], &out, created)
+        })
+        .is_err());
+        let fso = crate::nar::nar_to_fsobject(&nar[..]).unwrap();
+        assert!(remove_on_error(&out, |created| restore_one(&fso, &out, created)).is_err());
+        assert_eq!(fs::read(out.join("theirs")).unwrap(), b"theirs");
+    }
+}
@@ -0,0 +1,155 @@
+//! Hashing an [`FsObject`] the way git hashes trees, so that an archive can be
+//! checked against the commit it claims to be from.
+
+use std::io::{self, Write};
+
+use crate::{
+    hash::{Hash, HashAlgo, NarHasher},
+    ByteStream, Executable, FsObject,
+};
+
+/// Which hash a repository names its objects by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ObjectFormat {
+    Sha1,
+    Sha256,
+}
+
+impl ObjectFormat {
+    fn algo(self) -> HashAlgo {
+        match self {
+            ObjectFormat::Sha1 => HashAlgo::Sha1,
+            ObjectFormat::Sha256 => HashAlgo::Sha256,
+        }
+    }
+}
+
+/// Mode of an object in its parent tree. Git writes directories without the
+/// leading zero that `git ls-tree` shows.
+fn mode<T: ByteStream>(obj: &FsObject<T>) -> &'static [u8] {
+    match obj {
+        FsObject::File(Executable::NotExecutable, _) => b"100644",
+        FsObject::File(Executable::IsExecutable, _) => b"100755",
+        FsObject::Symlink(_) => b"120000",
+        FsObject::Directory(_) => b"40000",
+    }
+}
+
+fn hash_object(
+    format: ObjectFormat,
+    kind: &str,
+    len: usize,
+    write: impl FnOnce(&mut NarHasher) -> io::Result<()>,
+) -> io::Result<Hash> {
+    let mut hasher = NarHasher::with_algo(format.algo());
+    write!(hasher, "{kind} {len}\0")?;
+    write(&mut hasher)?;
+    Ok(hasher.finish())
+}
+
+/// Computes the id git would give `obj`: a tree for a directory, or a blob
+/// for a file or a symlink's target.
+///
+/// Git has no empty directories, and GitHub archives have submodules as empty
+/// directories, so those only match if the tree really has none.
+pub fn object_id<T: ByteStream>(obj: &FsObject<T>, format: ObjectFormat) -> io::Result<Hash> {
+    match obj {
+        FsObject::File(_, contents) => {
+            hash_object(format, "blob", contents.len(), |h| contents.write_into(h))
+        }
+        FsObject::Symlink(target) => {
+            let target = target.as_bytes();
+            hash_object(format, "blob", target.len(), |h| h.write_all(target))
+        }
+        FsObject::Directory(entries) => {
+            // git sorts directories as if their names ended in a slash
+            let mut children = entries
+                .0
+                .iter()
+                .map(|(name, child)| {
+                    let mut key = name.clone();
+                    if let FsObject::Directory(_) = **child {
+                        key.push(b'/');
+                    }
+                    (key, name, child)
+                })
+                .collect::<Vec<_>>();
+            children.sort_by(|a, b| a.0.cmp(&b.0));
+
+            let mut tree = Vec::new();
+            for (_, name, child) in children {
+                tree.extend_from_slice(mode(child));
+                tree.push(b' ');
+                tree.extend_from_slice(name);
+                tree.push(0);
+                tree.extend_from_slice(&object_id(child, format)?.bytes);
+            }
+            hash_object(format, "tree", tree.len(), |h| h.write_all(&tree))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{nar::nar_to_fsobject, Directory};
+
+    // Expected ids are from `git write-tree` in repositories of each format.
+
+    fn check(nar: &[u8], sha1: &str, sha256: &str) {
+        let fso = nar_to_fsobject(nar).unwrap();
+        assert_eq!(object_id(&fso, ObjectFormat::Sha1).unwrap().to_hex(), sha1);
+        assert_eq!(
+            object_id(&fso, ObjectFormat::Sha256).unwrap().to_hex(),
+            sha256
+        );
+    }
+
+    #[test]
+    fn reference_trees() {
+        check(
+            include_bytes!("testdata/test2.nar"),
+            "9e584a1c6f1396226446a515f9aaeb50fdfa9c43",
+            "e35c08aecfda86ddace900ea8d0619c0d2ba1b65efa567dc23ca37df8125d7c1",
+        );
+        check(
+            include_bytes!("testdata/links.nar"),
+            "5db56eee1841f6b174e03e092dc4f2faf077cb4c",
+            "9b038b9124f3b8d7ffc65e81bddb8bf0113b012335b06e8e3bfaa51d98dbaeb9",
+        );
+    }
+
+    #[test]
+    fn empty_tree() {
+        let empty = FsObject::<crate::ConstByteStream>::Directory(Directory::default());
+        assert_eq!(
+            object_id(&empty, ObjectFormat::Sha1).unwrap().to_hex(),
+            "4b825dc642cb6eb9a060e54bf8d69288fbee4904"
+        );
+    }
+
+    #[test]
+    fn sort_order() {
+        let file = |s: &str| {
+            FsObject::File(
+                Executable::NotExecutable,
+                crate::ConstByteStream(s.as_bytes().to_vec()),
+            )
+        };
+        let mut tree = Directory::default();
+        for (path, contents) in [("a/x", "x\n"), ("a-b", "y\n"), ("a0", "z\n")] {
+            tree.insert(&path.as_bytes().try_into().unwrap(), file(contents))
+                .unwrap();
+        }
+        let tree = FsObject::Directory(tree);
+
+        assert_eq!(
+            object_id(&tree, ObjectFormat::Sha1).unwrap().to_hex(),
+            "340e0ef53c454d2ce874db98379b327d04145cb5"
+        );
+        assert_eq!(
+            object_id(&tree, ObjectFormat::Sha256).unwrap().to_hex(),
+            "61d7fb7fce75a4ed45d912d7f40ce76cc4172bf2026f675483c5a147f149b7e4"
+        );
+    }
+}
@@ -1,33 +1,506 @@
 //! Implementation of hashing nar files.
 
-use std::io::Write;
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    str::FromStr,
+};
 
 use sha2::Digest;
+use thiserror::Error;
 
-pub struct NarHasher(sha2::Sha256);
+/// State of one of the supported hash functions.
+#[derive(Clone)]
+enum HashState {
+    Sha1(sha1::Sha1),
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl HashState {
+    fn new(algo: HashAlgo) -> HashState {
+        match algo {
+            HashAlgo::Sha1 => HashState::Sha1(Default::default()),
+            HashAlgo::Sha256 => HashState::Sha256(Default::default()),
+            HashAlgo::Sha512 => HashState::Sha512(Default::default()),
+            #[cfg(feature = "blake3")]
+            HashAlgo::Blake3 => HashState::Blake3(Default::default()),
+        }
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        match self {
+            HashState::Sha1(h) => h.update(buf),
+            HashState::Sha256(h) => h.update(buf),
+            HashState::Sha512(h) => h.update(buf),
+            #[cfg(feature = "blake3")]
+            HashState::Blake3(h) => {
+                h.update(buf);
+            }
+        }
+    }
+
+    fn finish(self) -> Hash {
+        let (algo, bytes) = match self {
+            HashState::Sha1(h) => (HashAlgo::Sha1, h.finalize().to_vec()),
+            HashState::Sha256(h) => (HashAlgo::Sha256, h.finalize().to_vec()),
+            HashState::Sha512(h) => (HashAlgo::Sha512, h.finalize().to_vec()),
+            #[cfg(feature = "blake3")]
+            HashState::Blake3(h) => (HashAlgo::Blake3, h.finalize().as_bytes().to_vec()),
+        };
+        Hash { algo, bytes }
+    }
+}
+
+/// Hashes whatever is written into it, by default with sha256 as Nix does for
+/// NAR hashes.
+#[derive(Clone)]
+pub struct NarHasher(HashState);
 
 /// Subresource integrity hash
 type SRIHash = String;
 
+impl Default for NarHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl NarHasher {
     pub fn new() -> NarHasher {
-        NarHasher(sha2::Sha256::default())
+        NarHasher::with_algo(HashAlgo::Sha256)
+    }
+
+    pub fn with_algo(algo: HashAlgo) -> NarHasher {
+        NarHasher(HashState::new(algo))
+    }
+
+    pub fn finish(self) -> Hash {
+        self.0.finish()
     }
 
     pub fn digest(self) -> SRIHash {
-        let digest = self.0.finalize();
-        let base64d = base64::encode(digest);
-        format!("sha256-{}", base64d)
+        self.finish().to_sri()
     }
 }
 
 impl Write for NarHasher {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.0.update(buf);
         Ok(buf.len())
     }
 
-    fn flush(&mut self) -> std::io::Result<()> {
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// How a file system object is turned into bytes to hash, following Nix's
+/// `FileIngestionMethod`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashMode {
+    /// Hash of the bytes of a single file, as used by `fetchurl`.
+    Flat,
+    /// Hash of the NAR serialisation, as used by `fetchzip` and friends.
+    Recursive,
+}
+
+/// Hashes the raw bytes of `r`, for [`HashMode::Flat`].
+pub fn flat_hash(mut r: impl Read, algo: HashAlgo) -> io::Result<Hash> {
+    let mut hasher = NarHasher::with_algo(algo);
+    io::copy(&mut r, &mut hasher)?;
+    Ok(hasher.finish())
+}
+
+/// Computes several hashes of the same data in one pass over it.
+#[derive(Clone)]
+pub struct MultiHasher(Vec<HashState>);
+
+impl MultiHasher {
+    pub fn new(algos: &[HashAlgo]) -> MultiHasher {
+        MultiHasher(algos.iter().map(|&a| HashState::new(a)).collect())
+    }
+
+    /// Returns the hashes in the same order as the algorithms were given.
+    pub fn finish(self) -> Vec<Hash> {
+        self.0.into_iter().map(HashState::finish).collect()
+    }
+}
+
+impl Write for MultiHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for h in &mut self.0 {
+            h.update(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
         Ok(())
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HashAlgo {
+    Sha1,
+    Sha256,
+    Sha512,
+    #[cfg(feature = "blake3")]
+    Blake3,
+}
+
+impl HashAlgo {
+    /// Name as used by Nix and in SRI hashes.
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgo::Sha1 => "sha1",
+            HashAlgo::Sha256 => "sha256",
+            HashAlgo::Sha512 => "sha512",
+            #[cfg(feature = "blake3")]
+            HashAlgo::Blake3 => "blake3",
+        }
+    }
+
+    /// Size of the digest in bytes.
+    pub fn size(self) -> usize {
+        match self {
+            HashAlgo::Sha1 => 20,
+            HashAlgo::Sha256 => 32,
+            HashAlgo::Sha512 => 64,
+            #[cfg(feature = "blake3")]
+            HashAlgo::Blake3 => 32,
+        }
+    }
+}
+
+impl fmt::Display for HashAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for HashAlgo {
+    type Err = HashParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha1" => Ok(HashAlgo::Sha1),
+            "sha256" => Ok(HashAlgo::Sha256),
+            "sha512" => Ok(HashAlgo::Sha512),
+            #[cfg(feature = "blake3")]
+            "blake3" => Ok(HashAlgo::Blake3),
+            _ => Err(HashParseError::UnknownAlgo(s.to_string())),
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum HashParseError {
+    #[error("unknown hash algorithm {0:?}")]
+    UnknownAlgo(String),
+    #[error("hash {0:?} does not say which algorithm it uses")]
+    MissingAlgo(String),
+    #[error("hash {0:?} is not of a valid length for {1}")]
+    BadLength(String, HashAlgo),
+    #[error("invalid character in hash {0:?}")]
+    BadCharacter(String),
+    #[error("hash {hash:?} is {got}, but {expected} was expected")]
+    AlgoMismatch {
+        hash: String,
+        got: HashAlgo,
+        expected: HashAlgo,
+    },
+}
+
+/// Alphabet of Nix's base32, which is missing e, o, t and u.
+const BASE32_CHARS: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+fn base32_len(size: usize) -> usize {
+    if size == 0 {
+        return 0;
+    }
+    (size * 8 - 1) / 5 + 1
+}
+
+/// Encodes into Nix's idiosyncratic base32, which starts from the end of the
+/// input and is not compatible with RFC 4648.
+pub fn to_nix_base32(bytes: &[u8]) -> String {
+    let len = base32_len(bytes.len());
+    let mut out = String::with_capacity(len);
+
+    for n in (0..len).rev() {
+        let b = n * 5;
+        let i = b / 8;
+        let j = b % 8;
+        let low = bytes[i] >> j;
+        let high = if i + 1 < bytes.len() {
+            bytes[i + 1].checked_shl(8 - j as u32).unwrap_or(0)
+        } else {
+            0
+        };
+        out.push(BASE32_CHARS[((low | high) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+/// Decodes Nix base32 into `size` bytes. Returns `None` if it is not valid
+/// base32 or does not fit.
+pub fn from_nix_base32(s: &str, size: usize) -> Option<Vec<u8>> {
+    if s.len() != base32_len(size) {
+        return None;
+    }
+    let mut out = vec![0u8; size];
+
+    for (n, c) in s.bytes().rev().enumerate() {
+        let digit = BASE32_CHARS.iter().position(|&d| d == c)? as u8;
+        let b = n * 5;
+        let i = b / 8;
+        let j = b % 8;
+        out[i] |= digit << j;
+        let carry = digit.checked_shr(8 - j as u32).unwrap_or(0);
+        if i + 1 < size {
+            out[i + 1] |= carry;
+        } else if carry != 0 {
+            return None;
+        }
+    }
+    Some(out)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// A digest together with the algorithm that made it.
+///
+/// Displays as an SRI hash.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Hash {
+    pub algo: HashAlgo,
+    pub bytes: Vec<u8>,
+}
+
+impl Hash {
+    /// Parses a hash in any of the formats Nix accepts:
+    ///
+    /// - SRI: `sha256-<base64>`
+    /// - Typed: `sha256:<hex, base32 or base64>`
+    /// - Bare hex, Nix base32 or base64, if `algo` is given
+    ///
+    /// If `algo` is given, the hash must be of that algorithm.
+    pub fn parse(s: &str, algo: Option<HashAlgo>) -> Result<Hash, HashParseError> {
+        let (parsed_algo, rest, sri) = if let Some((name, rest)) = s.split_once(':') {
+            (Some(name.parse::<HashAlgo>()?), rest, false)
+        } else if let Some((name, rest)) = s.split_once('-') {
+            (Some(name.parse::<HashAlgo>()?), rest, true)
+        } else {
+            (None, s, false)
+        };
+
+        let hash_algo = match (parsed_algo, algo) {
+            (Some(got), Some(expected)) if got != expected => {
+                return Err(HashParseError::AlgoMismatch {
+                    hash: s.to_string(),
+                    got,
+                    expected,
+                })
+            }
+            (Some(a), _) | (None, Some(a)) => a,
+            (None, None) => return Err(HashParseError::MissingAlgo(s.to_string())),
+        };
+
+        let size = hash_algo.size();
+        let base64_len = size.div_ceil(3) * 4;
+        let bytes = if sri {
+            if rest.len() != base64_len {
+                return Err(HashParseError::BadLength(s.to_string(), hash_algo));
+            }
+            base64::decode(rest).ok()
+        } else if rest.len() == size * 2 {
+            from_hex(rest)
+        } else if rest.len() == base32_len(size) {
+            from_nix_base32(rest, size)
+        } else if rest.len() == base64_len {
+            base64::decode(rest).ok()
+        } else {
+            return Err(HashParseError::BadLength(s.to_string(), hash_algo));
+        };
+
+        match bytes {
+            Some(bytes) if bytes.len() == size => Ok(Hash {
+                algo: hash_algo,
+                bytes,
+            }),
+            _ => Err(HashParseError::BadCharacter(s.to_string())),
+        }
+    }
+
+    /// `sha256-<base64>`
+    pub fn to_sri(&self) -> String {
+        format!("{}-{}", self.algo, base64::encode(&self.bytes))
+    }
+
+    /// `sha256:<base32>`, the form Nix uses in store path fingerprints.
+    pub fn to_typed_base32(&self) -> String {
+        format!("{}:{}", self.algo, self.to_nix_base32())
+    }
+
+    pub fn to_nix_base32(&self) -> String {
+        to_nix_base32(&self.bytes)
+    }
+
+    pub fn to_hex(&self) -> String {
+        to_hex(&self.bytes)
+    }
+}
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_sri())
+    }
+}
+
+impl fmt::Debug for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Hash").field(&self.to_sri()).finish()
+    }
+}
+
+impl FromStr for Hash {
+    type Err = HashParseError;
+
+    /// Parses a hash which says which algorithm it uses, i.e. an SRI or typed
+    /// hash.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Hash::parse(s, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // sha256("abc"), as converted by `nix hash convert`
+    const SRI: &str = "sha256-ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=";
+    const BASE32: &str = "1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s";
+    const HEX: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn formats() {
+        let h: Hash = SRI.parse().unwrap();
+        assert_eq!(h.to_hex(), HEX);
+        assert_eq!(h.to_nix_base32(), BASE32);
+        assert_eq!(h.to_typed_base32(), format!("sha256:{BASE32}"));
+        assert_eq!(h.to_string(), SRI);
+    }
+
+    #[test]
+    fn parses_everything() {
+        let h: Hash = SRI.parse().unwrap();
+        for s in [
+            format!("sha256:{BASE32}"),
+            format!("sha256:{HEX}"),
+            "sha256:ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=".to_string(),
+        ] {
+            assert_eq!(s.parse::<Hash>().unwrap(), h, "{s}");
+        }
+        for s in [BASE32, HEX, SRI] {
+            assert_eq!(Hash::parse(s, Some(HashAlgo::Sha256)).unwrap(), h, "{s}");
+        }
+    }
+
+    #[test]
+    fn rejects_bad_hashes() {
+        assert_eq!(
+            BASE32.parse::<Hash>(),
+            Err(HashParseError::MissingAlgo(BASE32.into()))
+        );
+        assert!(matches!(
+            Hash::parse(SRI, Some(HashAlgo::Sha512)),
+            Err(HashParseError::AlgoMismatch { .. })
+        ));
+        // 'e' is not in the alphabet
+        assert!(matches!(
+            Hash::parse(&BASE32.replace('1', "e"), Some(HashAlgo::Sha256)),
+            Err(HashParseError::BadCharacter(_))
+        ));
+        // the top bits of the first character do not fit in 32 bytes
+        assert!(matches!(
+            Hash::parse(&BASE32.replacen('1', "z", 1), Some(HashAlgo::Sha256)),
+            Err(HashParseError::BadCharacter(_))
+        ));
+        assert!(matches!(
+            Hash::parse("sha256:abc", None),
+            Err(HashParseError::BadLength(..))
+        ));
+    }
+
+    #[test]
+    fn base32_round_trip() {
+        for size in [20, 32, 64] {
+            let bytes = (0..size as u8)
+                .map(|b| b.wrapping_mul(37))
+                .collect::<Vec<_>>();
+            assert_eq!(from_nix_base32(&to_nix_base32(&bytes), size), Some(bytes));
+        }
+    }
+
+    #[test]
+    fn base32_empty() {
+        assert_eq!(to_nix_base32(&[]), "");
+        assert_eq!(from_nix_base32("", 0), Some(Vec::new()));
+    }
+
+    #[test]
+    fn flat() {
+        assert_eq!(
+            flat_hash(&b"abc"[..], HashAlgo::Sha256).unwrap().to_sri(),
+            SRI
+        );
+    }
+
+    #[test]
+    fn algorithms() {
+        let mut hasher = MultiHasher::new(&[HashAlgo::Sha1, HashAlgo::Sha256, HashAlgo::Sha512]);
+        hasher.write_all(b"abc").unwrap();
+        let hashes = hasher.finish();
+        assert_eq!(
+            hashes.iter().map(Hash::to_hex).collect::<Vec<_>>(),
+            [
+                "a9993e364706816aba3e25717850c26c9cd0d89d",
+                HEX,
+                "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+                 2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f",
+            ]
+        );
+        assert!(hashes[0].to_sri().starts_with("sha1-"));
+        assert_eq!(hashes[1].to_sri(), SRI);
+        assert!(hashes[2].to_sri().starts_with("sha512-"));
+        assert_eq!(hashes[2].to_sri().parse::<Hash>().unwrap(), hashes[2]);
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn blake3() {
+        let mut hasher = NarHasher::with_algo(HashAlgo::Blake3);
+        hasher.write_all(b"abc").unwrap();
+        let hash = hasher.finish();
+        assert_eq!(
+            hash.to_hex(),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+        assert!(hash.to_sri().starts_with("blake3-"));
+    }
+}
@@ -1,15 +1,27 @@
 //! Virtual filesystem backed NAR file library.
 //!
 //! See Figure 5.2 of Eelco's thesis for details.
+pub mod compression;
+pub mod diff;
+#[cfg(unix)]
+pub mod fs;
+pub mod git;
 pub mod hash;
+pub mod ls;
+pub mod nar;
+pub mod narinfo;
+pub mod signing;
+pub mod store_path;
 pub mod tar;
+pub mod zip;
 use thiserror::Error;
 
 use std::{
-    collections::BTreeMap,
+    collections::{btree_map, BTreeMap},
     fmt,
     io::{self, Write},
     panic::Location,
+    path::PathBuf,
 };
 
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
@@ -18,6 +30,27 @@ pub type Error = Box<dyn std::error::Error + Send + Sync>;
 pub enum Tar2NarError {
     #[error("IO error {0} at {1}")]
     Io(io::Error, &'static Location<'static>),
+    #[error("hard link {link:?} points to {target:?}, which is not a file earlier in the archive")]
+    MissingLinkTarget { link: String, target: String },
+    #[error(
+        "expected exactly one file or directory at the top level of the archive, as Nix does \
+         when unpacking tarballs, but found {}",
+        describe_roots(.roots)
+    )]
+    NotSingleRoot { roots: Vec<String> },
+    #[error("archive member {path:?} {problem}")]
+    InvalidMember {
+        path: String,
+        problem: InvalidMember,
+    },
+    #[error("unsupported tar entry: {0}")]
+    Unsupported(tar::UnsupportedEntry),
+    #[error("refusing to restore into {}, which already exists", .0.display())]
+    TargetExists(PathBuf),
+    #[error("refusing to restore unsafe name {name:?} in {}", .dir.display())]
+    InvalidName { name: String, dir: PathBuf },
+    #[error(transparent)]
+    Nar(#[from] nar::NarError),
     #[error("Unknown error {0} at {1}")]
     Unknown(
         Box<dyn std::error::Error + Send + Sync>,
@@ -25,6 +58,29 @@ pub enum Tar2NarError {
     ),
 }
 
+/// Reasons an archive member cannot be put into a tree. Nix refuses `..` and
+/// extracting through anything that is not a directory; the rest would
+/// otherwise silently pick a winner.
+#[derive(Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvalidMember {
+    #[error("has a `..` component")]
+    ParentDir,
+    #[error("contains a NUL byte")]
+    Nul,
+    #[error("appears more than once")]
+    Duplicate,
+    #[error("is both a directory and something else")]
+    Conflict,
+}
+
+fn describe_roots(roots: &[String]) -> String {
+    if roots.is_empty() {
+        "nothing".into()
+    } else {
+        format!("{}: {}", roots.len(), roots.join(", "))
+    }
+}
+
 #[track_caller]
 pub(crate) fn io_error(ioe: io::Error) -> Tar2NarError {
     Tar2NarError::Io(ioe, std::panic::Location::caller())
@@ -41,11 +97,27 @@ pub enum Executable {
     NotExecutable,
 }
 
+impl Executable {
+    /// Whether a file with the Unix permissions `mode` is executable. Like
+    /// `nix-store --dump`, this only looks at the owner's bit.
+    pub fn from_mode(mode: u32) -> Executable {
+        if mode & 0o100 != 0 {
+            Executable::IsExecutable
+        } else {
+            Executable::NotExecutable
+        }
+    }
+}
+
 /// Generic byte stream. Allows for lazily reading it out, for instance by
 /// indexing a tar file then reading contents later.
 pub trait ByteStream {
     fn write_into(&self, w: &mut dyn Write) -> WriteResult;
     fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 type PathComponent = Vec<u8>;
@@ -104,7 +176,7 @@ impl TryFrom<&[u8]> for FileName {
             .map(|a| a.to_vec())
             .filter(|e| e != b"" && e != b".")
             .collect();
-        if bits.len() == 0 {
+        if bits.is_empty() {
             Err("empty file name")
         } else {
             Ok(Self(bits))
@@ -112,6 +184,37 @@ impl TryFrom<&[u8]> for FileName {
     }
 }
 
+/// Where a symlink points. Unlike a [`FileName`], this is kept byte for byte
+/// as it was found, since Nix stores whatever `readlink` returns.
+#[derive(PartialOrd, Ord, PartialEq, Eq, Clone)]
+pub struct SymlinkTarget(Vec<u8>);
+
+impl fmt::Debug for SymlinkTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SymlinkTarget")
+            .field(&DebugU8(&self.0))
+            .finish()
+    }
+}
+
+impl SymlinkTarget {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for SymlinkTarget {
+    fn from(value: Vec<u8>) -> Self {
+        Self(value)
+    }
+}
+
+impl From<&[u8]> for SymlinkTarget {
+    fn from(value: &[u8]) -> Self {
+        Self(value.to_vec())
+    }
+}
+
 #[derive(PartialOrd, Ord, PartialEq, Eq, Clone)]
 pub struct ConstByteStream(Vec<u8>);
 
@@ -152,7 +255,7 @@ impl<T: ByteStream> fmt::Debug for Directory<T> {
 pub enum FsObject<T: ByteStream> {
     File(Executable, T),
     Directory(Directory<T>),
-    Symlink(FileName),
+    Symlink(SymlinkTarget),
 }
 
 impl<T: PartialEq + ByteStream> PartialEq for FsObject<T> {
@@ -227,13 +330,42 @@ fn str(s: &[u8], w: &mut impl Write) -> WriteResult {
 }
 
 impl<T: ByteStream> Directory<T> {
-    pub fn insert(&mut self, path: &FileName, obj: FsObject<T>) -> Result<(), Error> {
-        match &path.0.as_slice() {
-            &[one] => {
-                // base case, a file at the root relative to me
-                self.0.insert(one.to_vec(), Box::new(obj));
+    /// Looks up the object at `path` relative to this directory.
+    pub fn get(&self, path: &FileName) -> Option<&FsObject<T>> {
+        let (last, parents) = path.0.split_last()?;
+        let mut dir = self;
+        for component in parents {
+            match &**dir.0.get(component)? {
+                FsObject::Directory(d) => dir = d,
+                _ => return None,
             }
-            &[top, ref rest @ ..] => {
+        }
+        dir.0.get(last).map(|b| &**b)
+    }
+
+    /// Puts `obj` at `path`, creating any missing parent directories.
+    ///
+    /// Inserting a directory where there already is one merges the two, since
+    /// archives often list a directory after things inside it.
+    pub fn insert(&mut self, path: &FileName, obj: FsObject<T>) -> Result<(), InvalidMember> {
+        match path.0.as_slice() {
+            [one] => match self.0.entry(one.to_vec()) {
+                btree_map::Entry::Vacant(v) => {
+                    v.insert(Box::new(obj));
+                }
+                btree_map::Entry::Occupied(mut o) => match (&mut **o.get_mut(), obj) {
+                    (FsObject::Directory(existing), FsObject::Directory(new)) => {
+                        for (name, child) in new.0 {
+                            existing.insert(&FileName::singleton(name), *child)?;
+                        }
+                    }
+                    (FsObject::Directory(_), _) | (_, FsObject::Directory(_)) => {
+                        return Err(InvalidMember::Conflict)
+                    }
+                    _ => return Err(InvalidMember::Duplicate),
+                },
+            },
+            [top, rest @ ..] => {
                 assert_ne!(top, b"");
                 let fso = self
                     .0
@@ -243,10 +375,10 @@ impl<T: ByteStream> Directory<T> {
                 if let FsObject::Directory(d) = &mut **fso {
                     d.insert(&FileName(rest.to_vec()), obj)?;
                 } else {
-                    return Err("attempt to insert into a non directory".into());
+                    return Err(InvalidMember::Conflict);
                 };
             }
-            &[] => {
+            [] => {
                 panic!("how did we get an empty path?");
             }
         }
@@ -255,6 +387,24 @@ impl<T: ByteStream> Directory<T> {
 }
 
 impl<T: ByteStream> FsObject<T> {
+    /// Reads every file into memory, which also makes trees with different
+    /// kinds of [`ByteStream`] comparable.
+    pub fn to_buffered(&self) -> io::Result<FsObject<ConstByteStream>> {
+        Ok(match self {
+            FsObject::File(exec, contents) => {
+                let mut v = Vec::with_capacity(contents.len());
+                contents.write_into(&mut v)?;
+                FsObject::File(*exec, ConstByteStream(v))
+            }
+            FsObject::Directory(d) => FsObject::Directory(Directory(
+                d.0.iter()
+                    .map(|(name, child)| Ok((name.clone(), Box::new(child.to_buffered()?))))
+                    .collect::<io::Result<_>>()?,
+            )),
+            FsObject::Symlink(to) => FsObject::Symlink(to.clone()),
+        })
+    }
+
     /// Equivalent to `serialise`
     pub fn serialise_toplevel(&self, w: &mut impl Write) -> WriteResult {
         str(b"nix-archive-1", w)?;
@@ -272,7 +422,31 @@ impl<T: ByteStream> FsObject<T> {
     ///
     /// This is equivalent to `serialise''` in Figure 5.2
     pub fn serialise_one(&self, w: &mut impl Write) -> WriteResult {
-        match self {
+        self.serialise_counted(&mut CountingWriter::new(w), false)
+            .map(|_| ())
+    }
+
+    /// Like [`FsObject::serialise_toplevel`], but also lists where each file's
+    /// contents ended up, for a binary cache's `.ls` file.
+    pub fn serialise_listed(&self, w: &mut impl Write) -> io::Result<ls::Listing> {
+        let mut w = CountingWriter::new(w);
+        str(b"nix-archive-1", &mut w)?;
+        str(b"(", &mut w)?;
+        let root = self.serialise_counted(&mut w, true)?;
+        str(b")", &mut w)?;
+        Ok(ls::Listing {
+            root: root.expect("listing was asked for"),
+        })
+    }
+
+    /// `serialise''`, which also builds the listing entry of this object if
+    /// `list` is set.
+    fn serialise_counted<W: Write>(
+        &self,
+        w: &mut CountingWriter<W>,
+        list: bool,
+    ) -> io::Result<Option<ls::Entry>> {
+        Ok(match self {
             FsObject::File(exec, content) => {
                 type_(b"regular", w)?;
                 if let Executable::IsExecutable = exec {
@@ -280,30 +454,68 @@ impl<T: ByteStream> FsObject<T> {
                     str(b"", w)?; // (sic)
                 }
                 str(b"contents", w)?;
+                let entry = ls::Entry::Regular {
+                    size: content.len() as u64,
+                    executable: *exec == Executable::IsExecutable,
+                    // past the length
+                    nar_offset: w.written + 8,
+                };
                 content.serialise_just(w)?;
+                list.then_some(entry)
             }
             FsObject::Directory(entries) => {
                 type_(b"directory", w)?;
 
                 // FIXME: assert that the thing is sorted
 
+                let mut listed = BTreeMap::new();
                 for (name, v) in &entries.0 {
                     str(b"entry", w)?;
                     str(b"(", w)?;
                     str(b"name", w)?;
-                    str(&name, w)?;
+                    str(name, w)?;
                     str(b"node", w)?;
-                    v.serialise_wrapped(w)?;
+                    str(b"(", w)?;
+                    if let Some(entry) = v.serialise_counted(w, list)? {
+                        listed.insert(name.clone(), entry);
+                    }
+                    str(b")", w)?;
                     str(b")", w)?;
                 }
+                list.then_some(ls::Entry::Directory(listed))
             }
-            FsObject::Symlink(name) => {
+            FsObject::Symlink(target) => {
                 type_(b"symlink", w)?;
                 str(b"target", w)?;
-                name.to_path().as_slice().serialise_just(w)?;
+                target.as_bytes().serialise_just(w)?;
+                list.then(|| ls::Entry::Symlink(target.clone()))
             }
-        }
-        Ok(())
+        })
+    }
+}
+
+/// Keeps track of how much has been written, so serialisation knows where
+/// in the nar it is.
+pub(crate) struct CountingWriter<W> {
+    inner: W,
+    pub(crate) written: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        CountingWriter { inner, written: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
 }
 
@@ -378,7 +590,7 @@ mod tests {
                     ConstByteStream(b"aaa\n".to_vec()),
                 ),
             ),
-            dir_entry(b"f2", FsObject::Symlink(FileName::singleton(b"f".to_vec()))),
+            dir_entry(b"f2", FsObject::Symlink(SymlinkTarget::from(&b"f"[..]))),
         ])))
     }
 
@@ -391,7 +603,7 @@ mod tests {
                     ConstByteStream(b"aaa\n".to_vec()),
                 ),
             ),
-            dir_entry(b"f2", FsObject::Symlink(FileName::singleton(b"f".to_vec()))),
+            dir_entry(b"f2", FsObject::Symlink(SymlinkTarget::from(&b"f"[..]))),
             dir_entry(
                 b"exe",
                 FsObject::File(Executable::IsExecutable, ConstByteStream(b"nya\n".to_vec())),
@@ -421,7 +633,7 @@ mod tests {
                     ),
                 ),
                 dir_entry(b"dire", FsObject::Directory(Directory(BTreeMap::default()))),
-                dir_entry(b"f2", FsObject::Symlink(FileName::singleton(b"f".to_vec()))),
+                dir_entry(b"f2", FsObject::Symlink(SymlinkTarget::from(&b"f"[..]))),
             ]))),
             include_bytes!("testdata/test1.nar"),
         );
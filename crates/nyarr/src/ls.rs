@@ -0,0 +1,206 @@
+//! The `.ls` listings binary caches publish next to each nar, which let
+//! `nix store ls` and `nix store cat` look inside a nar without fetching it.
+//!
+//! Build one with [`FsObject::serialise_listed`](crate::FsObject::serialise_listed),
+//! or from a nar that is not in memory with [`Listing::read_nar`].
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Read},
+};
+
+use serde_json::{json, Map, Value};
+
+use crate::{
+    nar::{EntryKind, NarError, NarStreamReader},
+    Executable, PathComponent, SymlinkTarget,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Entry {
+    Regular {
+        size: u64,
+        executable: bool,
+        /// Where the contents start in the nar.
+        nar_offset: u64,
+    },
+    Directory(BTreeMap<PathComponent, Entry>),
+    Symlink(SymlinkTarget),
+}
+
+impl Entry {
+    /// JSON has no way to write bytes that are not UTF-8, so names and targets
+    /// that are not get replacement characters, as they would from Nix.
+    fn to_json(&self) -> Value {
+        match self {
+            Entry::Regular {
+                size,
+                executable,
+                nar_offset,
+            } => {
+                let mut obj = json!({
+                    "type": "regular",
+                    "size": size,
+                    "narOffset": nar_offset,
+                });
+                if *executable {
+                    obj["executable"] = Value::Bool(true);
+                }
+                obj
+            }
+            Entry::Directory(entries) => {
+                let entries = entries
+                    .iter()
+                    .map(|(name, entry)| {
+                        (String::from_utf8_lossy(name).into_owned(), entry.to_json())
+                    })
+                    .collect::<Map<_, _>>();
+                json!({ "type": "directory", "entries": entries })
+            }
+            Entry::Symlink(target) => json!({
+                "type": "symlink",
+                "target": String::from_utf8_lossy(target.as_bytes()),
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Listing {
+    pub root: Entry,
+}
+
+impl Listing {
+    pub fn to_json(&self) -> Value {
+        json!({ "version": 1, "root": self.root.to_json() })
+    }
+
+    /// Lists the nar in `nar` as it is read, without keeping file contents.
+    pub fn read_nar(nar: impl Read) -> Result<Listing, NarError> {
+        let mut reader = NarStreamReader::new(nar);
+        let mut root = None;
+
+        while let Some(entry) = reader.next_entry()? {
+            let listed = match entry.kind {
+                EntryKind::Regular => Entry::Regular {
+                    size: entry.contents.remaining(),
+                    executable: entry.executable == Executable::IsExecutable,
+                    nar_offset: entry.contents.offset(),
+                },
+                EntryKind::Directory => Entry::Directory(BTreeMap::new()),
+                EntryKind::Symlink(target) => Entry::Symlink(target),
+            };
+
+            let Some(path) = entry.path else {
+                root = Some(listed);
+                continue;
+            };
+            let (name, parents) = path.0.split_last().expect("paths are not empty");
+            let mut dir = root.as_mut().expect("the root is read first");
+            for parent in parents {
+                let Entry::Directory(entries) = dir else {
+                    unreachable!("only directories have children")
+                };
+                dir = entries
+                    .get_mut(parent)
+                    .expect("parents are read before their children");
+            }
+            let Entry::Directory(entries) = dir else {
+                unreachable!("only directories have children")
+            };
+            entries.insert(name.clone(), listed);
+        }
+
+        Ok(Listing {
+            root: root.expect("the reader produces a root or an error"),
+        })
+    }
+
+    /// Writes the listing as it goes in `<hash>.ls`.
+    pub fn write_json(&self, w: impl io::Write) -> io::Result<()> {
+        serde_json::to_writer(w, &self.to_json()).map_err(io::Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{nar::nar_to_fsobject, ConstByteStream, Directory, Executable, FsObject};
+
+    fn check_offsets(
+        nar: &[u8],
+        path: &mut Vec<u8>,
+        entry: &Entry,
+        fso: &FsObject<ConstByteStream>,
+    ) {
+        match (entry, fso) {
+            (
+                Entry::Regular {
+                    size,
+                    executable,
+                    nar_offset,
+                },
+                FsObject::File(exec, contents),
+            ) => {
+                let start = *nar_offset as usize;
+                assert_eq!(&nar[start..start + *size as usize], contents.0, "{path:?}");
+                assert_eq!(*executable, *exec == Executable::IsExecutable);
+            }
+            (Entry::Directory(entries), FsObject::Directory(dir)) => {
+                assert_eq!(
+                    entries.keys().collect::<Vec<_>>(),
+                    dir.0.keys().collect::<Vec<_>>()
+                );
+                for (name, entry) in entries {
+                    let len = path.len();
+                    path.extend_from_slice(b"/");
+                    path.extend_from_slice(name);
+                    check_offsets(nar, path, entry, &dir.0[name]);
+                    path.truncate(len);
+                }
+            }
+            (Entry::Symlink(target), FsObject::Symlink(to)) => assert_eq!(target, to),
+            _ => panic!("{path:?} has the wrong type"),
+        }
+    }
+
+    #[test]
+    fn offsets_point_at_contents() {
+        for nar in [
+            &include_bytes!("testdata/test2.nar")[..],
+            &include_bytes!("testdata/links.nar")[..],
+        ] {
+            let fso = nar_to_fsobject(nar).unwrap().to_buffered().unwrap();
+            let mut out = Vec::new();
+            let listing = fso.serialise_listed(&mut out).unwrap();
+            assert_eq!(out, nar);
+            check_offsets(nar, &mut Vec::new(), &listing.root, &fso);
+            assert_eq!(Listing::read_nar(nar).unwrap(), listing);
+        }
+    }
+
+    #[test]
+    fn json() {
+        let mut dir = Directory::default();
+        dir.insert(
+            &b"bin/hello"[..].try_into().unwrap(),
+            FsObject::File(Executable::IsExecutable, ConstByteStream(b"hi\n".to_vec())),
+        )
+        .unwrap();
+        dir.insert(
+            &b"link"[..].try_into().unwrap(),
+            FsObject::Symlink(SymlinkTarget::from(&b"bin/hello"[..])),
+        )
+        .unwrap();
+        let listing = FsObject::Directory(dir)
+            .serialise_listed(&mut io::sink())
+            .unwrap();
+
+        let mut out = Vec::new();
+        listing.write_json(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"{"root":{"entries":{"bin":{"entries":{"hello":{"executable":true,"narOffset":400,"size":3,"type":"regular"}},"type":"directory"},"link":{"target":"bin/hello","type":"symlink"}},"type":"directory"},"version":1}"#
+        );
+    }
+}
@@ -0,0 +1,636 @@
+//! Reading nar files back into [`FsObject`]s.
+//!
+//! This is the inverse of [`FsObject::serialise_toplevel`], following the same
+//! grammar from Figure 5.2 of Eelco's thesis. Unlike the encoder, the decoder
+//! does not trust its input: padding must be zero, tokens must come in the
+//! expected order and directory entries must be sorted and unique.
+
+use std::io::{self, Read};
+
+use thiserror::Error;
+
+use crate::{
+    ConstByteStream, Directory, Executable, FileName, FsObject, PathComponent, SymlinkTarget,
+};
+
+/// Longest string we accept anywhere other than file contents. This is
+/// `PATH_MAX` on Linux, which bounds both names and symlink targets.
+const MAX_TOKEN_LEN: u64 = 4096;
+
+#[derive(Error, Debug)]
+pub enum NarError {
+    #[error("IO error {0} at offset {1}")]
+    Io(io::Error, u64),
+    #[error("unexpected end of file at offset {0}")]
+    UnexpectedEof(u64),
+    #[error("expected {expected} at offset {offset}, got {got:?}")]
+    UnexpectedToken {
+        expected: &'static str,
+        got: String,
+        offset: u64,
+    },
+    #[error("nonzero padding at offset {0}")]
+    BadPadding(u64),
+    #[error("string of length {len} at offset {offset} is too long")]
+    TooLong { len: u64, offset: u64 },
+    #[error("invalid entry name {name:?} at offset {offset}")]
+    InvalidName { name: String, offset: u64 },
+    #[error("entry {name:?} at offset {offset} is not sorted after {prev:?}")]
+    Unsorted {
+        name: String,
+        prev: String,
+        offset: u64,
+    },
+    #[error("duplicate entry {name:?} at offset {offset}")]
+    Duplicate { name: String, offset: u64 },
+    #[error("trailing data after the archive at offset {0}")]
+    TrailingData(u64),
+}
+
+fn lossy(s: &[u8]) -> String {
+    String::from_utf8_lossy(s).into_owned()
+}
+
+/// Checks a directory entry name the same way Nix does when restoring.
+pub(crate) fn valid_name(name: &[u8]) -> bool {
+    !(name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') || name.contains(&0))
+}
+
+/// Low level reader of the nar token stream, keeping track of where we are so
+/// that errors can point at the offending bytes.
+struct Parser<R> {
+    r: R,
+    offset: u64,
+}
+
+/// Tells running out of archive apart from other IO errors.
+fn read_error(e: io::Error, offset: u64) -> NarError {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        NarError::UnexpectedEof(offset)
+    } else {
+        NarError::Io(e, offset)
+    }
+}
+
+impl<R: Read> Parser<R> {
+    fn new(r: R) -> Self {
+        Parser { r, offset: 0 }
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), NarError> {
+        self.r
+            .read_exact(buf)
+            .map_err(|e| read_error(e, self.offset))?;
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+
+    fn read_len(&mut self) -> Result<u64, NarError> {
+        let mut buf = [0u8; 8];
+        self.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    /// Reads and checks the padding after a string of length `len`.
+    fn read_padding(&mut self, len: u64) -> Result<(), NarError> {
+        let padding_num = if len & 0x7 == 0 { 0 } else { 8 - (len & 0x7) };
+        let mut buf = [0u8; 8];
+        let start = self.offset;
+        self.read_exact(&mut buf[..padding_num as usize])?;
+        if buf.iter().any(|&b| b != 0) {
+            return Err(NarError::BadPadding(start));
+        }
+        Ok(())
+    }
+
+    /// Reads a `str(..)`, returning the offset it started at along with it.
+    fn read_str(&mut self) -> Result<(u64, Vec<u8>), NarError> {
+        let start = self.offset;
+        let len = self.read_len()?;
+        if len > MAX_TOKEN_LEN {
+            return Err(NarError::TooLong { len, offset: start });
+        }
+        let mut buf = vec![0u8; len as usize];
+        self.read_exact(&mut buf)?;
+        self.read_padding(len)?;
+        Ok((start, buf))
+    }
+
+    fn expect(&mut self, tok: &'static str) -> Result<(), NarError> {
+        let (offset, got) = self.read_str()?;
+        if got != tok.as_bytes() {
+            return Err(NarError::UnexpectedToken {
+                expected: tok,
+                got: lossy(&got),
+                offset,
+            });
+        }
+        Ok(())
+    }
+
+    fn expect_eof(&mut self) -> Result<(), NarError> {
+        let mut buf = [0u8; 1];
+        match self.r.read(&mut buf) {
+            Ok(0) => Ok(()),
+            Ok(_) => Err(NarError::TrailingData(self.offset)),
+            Err(e) => Err(NarError::Io(e, self.offset)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    Regular,
+    Directory,
+    Symlink(SymlinkTarget),
+}
+
+/// One object in a nar, as produced by [`NarStreamReader::next_entry`].
+pub struct Entry<'a, R> {
+    /// Path of the object relative to the root of the archive, or `None` for
+    /// the root object itself.
+    pub path: Option<FileName>,
+    pub kind: EntryKind,
+    /// Always [`Executable::NotExecutable`] for things that are not regular
+    /// files.
+    pub executable: Executable,
+    /// Contents of a regular file. Empty for anything else.
+    pub contents: Contents<'a, R>,
+}
+
+/// Reader over the contents of one regular file in a nar.
+///
+/// Anything left unread is skipped when the next entry is requested.
+pub struct Contents<'a, R> {
+    parser: &'a mut Parser<R>,
+    remaining: &'a mut u64,
+}
+
+impl<'a, R> Contents<'a, R> {
+    /// Number of bytes of the file that have not been read yet.
+    pub fn remaining(&self) -> u64 {
+        *self.remaining
+    }
+
+    /// Where in the nar the next byte of the file is.
+    pub fn offset(&self) -> u64 {
+        self.parser.offset
+    }
+}
+
+impl<'a, R: Read> Read for Contents<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = (buf.len() as u64).min(*self.remaining) as usize;
+        if max == 0 {
+            return Ok(0);
+        }
+        let got = self.parser.r.read(&mut buf[..max])?;
+        if got == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.parser.offset += got as u64;
+        *self.remaining -= got as u64;
+        Ok(got)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Start,
+    /// In the contents of a regular file of the given length.
+    InFile(u64),
+    /// Just finished the `)` of a node.
+    NodeDone,
+    /// Between entries of the innermost open directory.
+    InDirectory,
+    Done,
+}
+
+/// Event based nar parser which never holds more than one path's worth of the
+/// archive in memory.
+///
+/// Objects are yielded in archive order, so a directory always comes before
+/// its children. The same validation as [`nar_to_fsobject`] is applied as the
+/// archive is read, so an invalid archive may produce some entries before
+/// producing an error.
+pub struct NarStreamReader<R> {
+    parser: Parser<R>,
+    state: State,
+    /// Unread bytes of the current file's contents.
+    remaining: u64,
+    /// Path of the current object.
+    path: Vec<PathComponent>,
+    /// For each open directory, the last entry name in it, for checking
+    /// ordering.
+    dirs: Vec<Option<PathComponent>>,
+}
+
+impl<R: Read> NarStreamReader<R> {
+    pub fn new(nar: R) -> Self {
+        NarStreamReader {
+            parser: Parser::new(nar),
+            state: State::Start,
+            remaining: 0,
+            path: Vec::new(),
+            dirs: Vec::new(),
+        }
+    }
+
+    /// Current offset into the archive.
+    pub fn offset(&self) -> u64 {
+        self.parser.offset
+    }
+
+    /// Reads up to the next object in the archive, returning `None` at the end
+    /// of it.
+    pub fn next_entry(&mut self) -> Result<Option<Entry<'_, R>>, NarError> {
+        loop {
+            match self.state {
+                State::Start => {
+                    self.parser.expect("nix-archive-1")?;
+                    self.parser.expect("(")?;
+                    return self.node_header().map(Some);
+                }
+                State::InFile(len) => {
+                    let skipped = io::copy(
+                        &mut Contents {
+                            parser: &mut self.parser,
+                            remaining: &mut self.remaining,
+                        },
+                        &mut io::sink(),
+                    );
+                    skipped.map_err(|e| read_error(e, self.parser.offset))?;
+                    self.parser.read_padding(len)?;
+                    self.parser.expect(")")?;
+                    self.state = State::NodeDone;
+                }
+                State::NodeDone => {
+                    if self.dirs.is_empty() {
+                        self.parser.expect_eof()?;
+                        self.state = State::Done;
+                    } else {
+                        // close the entry this node was in
+                        self.parser.expect(")")?;
+                        self.path.pop();
+                        self.state = State::InDirectory;
+                    }
+                }
+                State::InDirectory => {
+                    let (offset, tok) = self.parser.read_str()?;
+                    match tok.as_slice() {
+                        b")" => {
+                            self.dirs.pop();
+                            self.state = State::NodeDone;
+                        }
+                        b"entry" => {
+                            self.entry_name()?;
+                            return self.node_header().map(Some);
+                        }
+                        _ => {
+                            return Err(NarError::UnexpectedToken {
+                                expected: "entry or )",
+                                got: lossy(&tok),
+                                offset,
+                            })
+                        }
+                    }
+                }
+                State::Done => return Ok(None),
+            }
+        }
+    }
+
+    /// Reads the start of a directory entry up to its node, pushing its name
+    /// onto the current path.
+    fn entry_name(&mut self) -> Result<(), NarError> {
+        self.parser.expect("(")?;
+        self.parser.expect("name")?;
+        let (offset, name) = self.parser.read_str()?;
+        if !valid_name(&name) {
+            return Err(NarError::InvalidName {
+                name: lossy(&name),
+                offset,
+            });
+        }
+
+        let prev = self
+            .dirs
+            .last_mut()
+            .expect("entries are only read inside directories");
+        match prev {
+            Some(prev) if &name == prev => {
+                return Err(NarError::Duplicate {
+                    name: lossy(&name),
+                    offset,
+                })
+            }
+            Some(prev) if &name < prev => {
+                return Err(NarError::Unsorted {
+                    name: lossy(&name),
+                    prev: lossy(prev),
+                    offset,
+                })
+            }
+            _ => {}
+        }
+        *prev = Some(name.clone());
+
+        self.parser.expect("node")?;
+        self.parser.expect("(")?;
+        self.path.push(name);
+        Ok(())
+    }
+
+    /// Reads the start of a node just after its `(`, up to its contents if
+    /// it is a regular file.
+    ///
+    /// This is the inverse of `serialise''` in Figure 5.2.
+    fn node_header(&mut self) -> Result<Entry<'_, R>, NarError> {
+        self.parser.expect("type")?;
+        let (offset, ty) = self.parser.read_str()?;
+        let (kind, executable) = match ty.as_slice() {
+            b"regular" => {
+                let (offset, tok) = self.parser.read_str()?;
+                let exec = match tok.as_slice() {
+                    b"executable" => {
+                        self.parser.expect("")?;
+                        self.parser.expect("contents")?;
+                        Executable::IsExecutable
+                    }
+                    b"contents" => Executable::NotExecutable,
+                    _ => {
+                        return Err(NarError::UnexpectedToken {
+                            expected: "executable or contents",
+                            got: lossy(&tok),
+                            offset,
+                        })
+                    }
+                };
+                let len = self.parser.read_len()?;
+                self.remaining = len;
+                self.state = State::InFile(len);
+                (EntryKind::Regular, exec)
+            }
+            b"symlink" => {
+                self.parser.expect("target")?;
+                let (_, target) = self.parser.read_str()?;
+                let target = SymlinkTarget::from(target);
+                self.parser.expect(")")?;
+                self.state = State::NodeDone;
+                (EntryKind::Symlink(target), Executable::NotExecutable)
+            }
+            b"directory" => {
+                self.dirs.push(None);
+                self.state = State::InDirectory;
+                (EntryKind::Directory, Executable::NotExecutable)
+            }
+            _ => {
+                return Err(NarError::UnexpectedToken {
+                    expected: "regular, symlink or directory",
+                    got: lossy(&ty),
+                    offset,
+                })
+            }
+        };
+
+        if kind != EntryKind::Regular {
+            self.remaining = 0;
+        }
+
+        Ok(Entry {
+            path: (!self.path.is_empty()).then(|| FileName(self.path.clone())),
+            kind,
+            executable,
+            contents: Contents {
+                parser: &mut self.parser,
+                remaining: &mut self.remaining,
+            },
+        })
+    }
+}
+
+/// Parses a whole nar file into an in-memory [`FsObject`].
+///
+/// The entire archive, including file contents, is read into memory. Use
+/// [`NarStreamReader`] to avoid that.
+pub fn nar_to_fsobject(nar: impl Read) -> Result<FsObject<ConstByteStream>, NarError> {
+    let mut reader = NarStreamReader::new(nar);
+    let mut root = None;
+
+    while let Some(mut entry) = reader.next_entry()? {
+        let obj = match entry.kind {
+            EntryKind::Regular => {
+                let mut v = Vec::new();
+                entry
+                    .contents
+                    .read_to_end(&mut v)
+                    .map_err(|e| read_error(e, entry.contents.parser.offset))?;
+                FsObject::File(entry.executable, ConstByteStream(v))
+            }
+            EntryKind::Directory => FsObject::Directory(Directory::default()),
+            EntryKind::Symlink(target) => FsObject::Symlink(target),
+        };
+
+        match (&mut root, entry.path) {
+            (_, None) => root = Some(obj),
+            (Some(FsObject::Directory(d)), Some(path)) => d
+                .insert(&path, obj)
+                .expect("parents are read before their children"),
+            _ => unreachable!("the root comes first, and only directories have children"),
+        }
+    }
+
+    Ok(root.expect("the reader produces a root or an error"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{basic_tree, basic_tree_2};
+
+    use super::*;
+
+    fn serialise(obj: &FsObject<ConstByteStream>) -> Vec<u8> {
+        let mut out = Vec::new();
+        obj.serialise_toplevel(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn parses_reference_nars() {
+        let fso = nar_to_fsobject(&include_bytes!("testdata/test1.nar")[..]).unwrap();
+        assert_eq!(fso, basic_tree());
+        let fso = nar_to_fsobject(&include_bytes!("testdata/test2.nar")[..]).unwrap();
+        assert_eq!(fso, basic_tree_2());
+    }
+
+    #[test]
+    fn round_trip() {
+        for nar in [
+            &include_bytes!("testdata/test2.nar")[..],
+            include_bytes!("testdata/links.nar"),
+        ] {
+            let fso = nar_to_fsobject(nar).unwrap();
+            assert_eq!(serialise(&fso), nar);
+        }
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut nar = include_bytes!("testdata/test1.nar").to_vec();
+        nar[8] = b'm';
+        assert!(matches!(
+            nar_to_fsobject(&nar[..]),
+            Err(NarError::UnexpectedToken { offset: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn bad_padding() {
+        // "nix-archive-1" is 13 bytes long, so it is followed by 3 bytes of
+        // padding starting at 8 + 13.
+        let mut nar = include_bytes!("testdata/test1.nar").to_vec();
+        nar[22] = 1;
+        assert!(matches!(
+            nar_to_fsobject(&nar[..]),
+            Err(NarError::BadPadding(21))
+        ));
+    }
+
+    #[test]
+    fn truncated() {
+        let nar = include_bytes!("testdata/test1.nar");
+        assert!(matches!(
+            nar_to_fsobject(&nar[..nar.len() - 8]),
+            Err(NarError::UnexpectedEof(_))
+        ));
+
+        // in the middle of a file's contents
+        let nar = serialise(&basic_tree_2());
+        let contents = nar.windows(4).position(|w| w == b"nya\n").unwrap();
+        assert!(matches!(
+            nar_to_fsobject(&nar[..contents + 1]),
+            Err(NarError::UnexpectedEof(off)) if off == contents as u64 + 1
+        ));
+    }
+
+    #[test]
+    fn trailing_data() {
+        let mut nar = include_bytes!("testdata/test1.nar").to_vec();
+        let len = nar.len() as u64;
+        nar.push(0);
+        assert!(matches!(
+            nar_to_fsobject(&nar[..]),
+            Err(NarError::TrailingData(off)) if off == len
+        ));
+    }
+
+    /// Serialises a directory with entries in exactly the given order, which
+    /// the encoder itself will never do.
+    fn raw_dir(names: &[&[u8]]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for tok in [&b"nix-archive-1"[..], b"(", b"type", b"directory"] {
+            crate::str(tok, &mut out).unwrap();
+        }
+        for name in names {
+            for tok in [&b"entry"[..], b"(", b"name", name, b"node"] {
+                crate::str(tok, &mut out).unwrap();
+            }
+            FsObject::<ConstByteStream>::Directory(Directory::default())
+                .serialise_wrapped(&mut out)
+                .unwrap();
+            crate::str(b")", &mut out).unwrap();
+        }
+        crate::str(b")", &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn sorted_entries() {
+        assert!(nar_to_fsobject(&raw_dir(&[b"a", b"b"])[..]).is_ok());
+        assert!(matches!(
+            nar_to_fsobject(&raw_dir(&[b"b", b"a"])[..]),
+            Err(NarError::Unsorted { .. })
+        ));
+        assert!(matches!(
+            nar_to_fsobject(&raw_dir(&[b"a", b"a"])[..]),
+            Err(NarError::Duplicate { .. })
+        ));
+    }
+
+    #[test]
+    fn invalid_names() {
+        for name in [&b""[..], b".", b"..", b"a/b", b"a\0"] {
+            assert!(
+                matches!(
+                    nar_to_fsobject(&raw_dir(&[name])[..]),
+                    Err(NarError::InvalidName { .. })
+                ),
+                "{:?}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn stream_entries() {
+        let mut reader = NarStreamReader::new(&include_bytes!("testdata/test2.nar")[..]);
+        let mut seen = Vec::new();
+        while let Some(mut entry) = reader.next_entry().unwrap() {
+            let mut contents = Vec::new();
+            // leave the contents of f unread, it should get skipped
+            let path = entry.path.map(|p| lossy(&p.to_path()));
+            if path.as_deref() != Some("f") {
+                entry.contents.read_to_end(&mut contents).unwrap();
+            }
+            seen.push((path, entry.kind, entry.executable, contents));
+        }
+        assert_eq!(
+            seen,
+            vec![
+                (
+                    None,
+                    EntryKind::Directory,
+                    Executable::NotExecutable,
+                    vec![]
+                ),
+                (
+                    Some("exe".into()),
+                    EntryKind::Regular,
+                    Executable::IsExecutable,
+                    b"nya\n".to_vec()
+                ),
+                (
+                    Some("f".into()),
+                    EntryKind::Regular,
+                    Executable::NotExecutable,
+                    vec![]
+                ),
+                (
+                    Some("f2".into()),
+                    EntryKind::Symlink(SymlinkTarget::from(&b"f"[..])),
+                    Executable::NotExecutable,
+                    vec![]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn stream_reports_errors_late() {
+        let mut nar = raw_dir(&[b"b", b"a"]);
+        nar.truncate(nar.len() - 8);
+        let mut reader = NarStreamReader::new(&nar[..]);
+        assert_eq!(
+            reader.next_entry().unwrap().unwrap().kind,
+            EntryKind::Directory
+        );
+        assert_eq!(
+            reader.next_entry().unwrap().unwrap().kind,
+            EntryKind::Directory
+        );
+        assert!(matches!(
+            reader.next_entry(),
+            Err(NarError::Unsorted { .. })
+        ));
+    }
+}
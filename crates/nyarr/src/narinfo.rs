@@ -0,0 +1,504 @@
+//! Reading and writing `.narinfo` files, which describe a store path in a
+//! binary cache and where to find its nar.
+
+use std::{fmt, str::FromStr};
+
+use thiserror::Error;
+
+use crate::{
+    compression::Compression,
+    hash::{Hash, HashMode, HashParseError},
+    signing::{PublicKey, SecretKey},
+    store_path::{StorePath, StorePathError},
+};
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum NarInfoError {
+    #[error("narinfo line {0:?} is not `Key: value`")]
+    BadLine(String),
+    #[error("narinfo has no {0}")]
+    MissingField(&'static str),
+    #[error("narinfo has {0} more than once")]
+    DuplicateField(&'static str),
+    #[error("invalid {field} {value:?}")]
+    BadValue { field: &'static str, value: String },
+    #[error(transparent)]
+    StorePath(#[from] StorePathError),
+    #[error(transparent)]
+    Hash(#[from] HashParseError),
+}
+
+/// A signature over a path's fingerprint, `<key name>:<base64>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Signature {
+    pub key_name: String,
+    pub sig: Vec<u8>,
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.key_name, base64::encode(&self.sig))
+    }
+}
+
+impl FromStr for Signature {
+    type Err = NarInfoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || NarInfoError::BadValue {
+            field: "Sig",
+            value: s.to_string(),
+        };
+        let (key_name, sig) = s.split_once(':').ok_or_else(bad)?;
+        if key_name.is_empty() {
+            return Err(bad());
+        }
+        Ok(Signature {
+            key_name: key_name.to_string(),
+            sig: base64::decode(sig).map_err(|_| bad())?,
+        })
+    }
+}
+
+/// How a content-addressed path's hash was computed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ContentAddress {
+    /// `text:<hash>`, as `builtins.toFile` makes.
+    Text(Hash),
+    /// `fixed:<hash>` or `fixed:r:<hash>`, as fixed-output derivations make.
+    Fixed(HashMode, Hash),
+}
+
+impl fmt::Display for ContentAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContentAddress::Text(hash) => write!(f, "text:{}", hash.to_typed_base32()),
+            ContentAddress::Fixed(HashMode::Flat, hash) => {
+                write!(f, "fixed:{}", hash.to_typed_base32())
+            }
+            ContentAddress::Fixed(HashMode::Recursive, hash) => {
+                write!(f, "fixed:r:{}", hash.to_typed_base32())
+            }
+        }
+    }
+}
+
+impl FromStr for ContentAddress {
+    type Err = NarInfoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(hash) = s.strip_prefix("text:") {
+            Ok(ContentAddress::Text(hash.parse()?))
+        } else if let Some(hash) = s.strip_prefix("fixed:r:") {
+            Ok(ContentAddress::Fixed(HashMode::Recursive, hash.parse()?))
+        } else if let Some(hash) = s.strip_prefix("fixed:") {
+            Ok(ContentAddress::Fixed(HashMode::Flat, hash.parse()?))
+        } else {
+            Err(NarInfoError::BadValue {
+                field: "CA",
+                value: s.to_string(),
+            })
+        }
+    }
+}
+
+/// What a narinfo says its nar is compressed with.
+///
+/// Nix can use any filter libarchive has, such as `br` or `lz4`. Those that
+/// [`Compression`] cannot decode are kept by name, so they are written back
+/// unchanged.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NarCompression {
+    Known(Compression),
+    Other(String),
+}
+
+impl NarCompression {
+    pub fn from_name(name: &str) -> NarCompression {
+        match Compression::from_name(name) {
+            Some(c) => NarCompression::Known(c),
+            None => NarCompression::Other(name.to_string()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            NarCompression::Known(c) => c.name(),
+            NarCompression::Other(name) => name,
+        }
+    }
+
+    /// The compression, if it is one nyarr can decode.
+    pub fn known(&self) -> Option<Compression> {
+        match self {
+            NarCompression::Known(c) => Some(*c),
+            NarCompression::Other(_) => None,
+        }
+    }
+}
+
+impl From<Compression> for NarCompression {
+    fn from(c: Compression) -> Self {
+        NarCompression::Known(c)
+    }
+}
+
+impl fmt::Display for NarCompression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The contents of a `.narinfo` file.
+///
+/// References and the deriver are in the same store as `store_path`, since
+/// narinfo files only give their base names.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NarInfo {
+    pub store_path: StorePath,
+    /// Where the nar is, relative to the cache.
+    pub url: String,
+    pub compression: NarCompression,
+    /// Hash of the nar as compressed.
+    pub file_hash: Option<Hash>,
+    pub file_size: Option<u64>,
+    pub nar_hash: Hash,
+    pub nar_size: u64,
+    pub references: Vec<StorePath>,
+    pub deriver: Option<StorePath>,
+    pub sigs: Vec<Signature>,
+    pub ca: Option<ContentAddress>,
+}
+
+impl NarInfo {
+    /// What gets signed: `1;<store path>;<nar hash>;<nar size>;<references>`.
+    /// Nix only trusts these with a sha256 nar hash.
+    ///
+    /// Nix takes the references from a set, so they are sorted here whatever
+    /// order the narinfo listed them in.
+    pub fn fingerprint(&self) -> String {
+        let mut references = self
+            .references
+            .iter()
+            .map(StorePath::to_string)
+            .collect::<Vec<_>>();
+        references.sort();
+        format!(
+            "1;{};{};{};{}",
+            self.store_path,
+            self.nar_hash.to_typed_base32(),
+            self.nar_size,
+            references.join(",")
+        )
+    }
+
+    /// Adds a signature by `key`, replacing any it already had.
+    pub fn sign(&mut self, key: &SecretKey) {
+        self.sigs.retain(|sig| sig.key_name != key.name);
+        self.sigs.push(key.sign(self.fingerprint().as_bytes()));
+    }
+
+    /// Whether any of the signatures is valid and by one of `keys`.
+    pub fn is_signed_by(&self, keys: &[PublicKey]) -> bool {
+        let fingerprint = self.fingerprint();
+        self.sigs.iter().any(|sig| {
+            keys.iter()
+                .any(|key| key.verify(fingerprint.as_bytes(), sig))
+        })
+    }
+}
+
+impl fmt::Display for NarInfo {
+    /// Writes the fields in the order Nix does.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "StorePath: {}", self.store_path)?;
+        writeln!(f, "URL: {}", self.url)?;
+        writeln!(f, "Compression: {}", self.compression)?;
+        if let Some(file_hash) = &self.file_hash {
+            writeln!(f, "FileHash: {}", file_hash.to_typed_base32())?;
+        }
+        if let Some(file_size) = self.file_size {
+            writeln!(f, "FileSize: {file_size}")?;
+        }
+        writeln!(f, "NarHash: {}", self.nar_hash.to_typed_base32())?;
+        writeln!(f, "NarSize: {}", self.nar_size)?;
+        let references = self
+            .references
+            .iter()
+            .map(StorePath::base_name)
+            .collect::<Vec<_>>();
+        writeln!(f, "References: {}", references.join(" "))?;
+        if let Some(deriver) = &self.deriver {
+            writeln!(f, "Deriver: {}", deriver.base_name())?;
+        }
+        for sig in &self.sigs {
+            writeln!(f, "Sig: {sig}")?;
+        }
+        if let Some(ca) = &self.ca {
+            writeln!(f, "CA: {ca}")?;
+        }
+        Ok(())
+    }
+}
+
+fn set<T>(slot: &mut Option<T>, field: &'static str, value: T) -> Result<(), NarInfoError> {
+    if slot.replace(value).is_some() {
+        return Err(NarInfoError::DuplicateField(field));
+    }
+    Ok(())
+}
+
+fn number(field: &'static str, value: &str) -> Result<u64, NarInfoError> {
+    value.parse().map_err(|_| NarInfoError::BadValue {
+        field,
+        value: value.to_string(),
+    })
+}
+
+impl FromStr for NarInfo {
+    type Err = NarInfoError;
+
+    /// Parses a narinfo the way Nix does: unknown fields are ignored, and
+    /// missing or empty compression means bzip2, which is what the first
+    /// caches used.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut store_path = None;
+        let mut url = None;
+        let mut compression = None;
+        let mut file_hash = None;
+        let mut file_size = None;
+        let mut nar_hash = None;
+        let mut nar_size = None;
+        let mut references = None;
+        let mut deriver = None;
+        let mut sigs = Vec::new();
+        let mut ca = None;
+
+        for line in s.lines().filter(|l| !l.is_empty()) {
+            let (key, value) = line
+                .split_once(": ")
+                .or_else(|| line.strip_suffix(':').map(|key| (key, "")))
+                .ok_or_else(|| NarInfoError::BadLine(line.to_string()))?;
+            match key {
+                "StorePath" => set(&mut store_path, "StorePath", StorePath::parse(value)?)?,
+                "URL" => set(&mut url, "URL", value.to_string())?,
+                "Compression" => set(&mut compression, "Compression", value.to_string())?,
+                "FileHash" => set(&mut file_hash, "FileHash", value.parse::<Hash>()?)?,
+                "FileSize" => set(&mut file_size, "FileSize", number("FileSize", value)?)?,
+                "NarHash" => set(&mut nar_hash, "NarHash", value.parse::<Hash>()?)?,
+                "NarSize" => set(&mut nar_size, "NarSize", number("NarSize", value)?)?,
+                "References" => set(&mut references, "References", value.to_string())?,
+                "Deriver" => set(&mut deriver, "Deriver", value.to_string())?,
+                "Sig" => sigs.push(value.parse()?),
+                "CA" => set(&mut ca, "CA", value.parse()?)?,
+                _ => {}
+            }
+        }
+
+        let store_path = store_path.ok_or(NarInfoError::MissingField("StorePath"))?;
+        let store_dir = &store_path.store_dir;
+        let references = references
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(|r| StorePath::from_base_name(store_dir, r))
+            .collect::<Result<_, _>>()?;
+        let deriver = match deriver.as_deref() {
+            None | Some("") | Some("unknown-deriver") => None,
+            Some(d) => Some(StorePath::from_base_name(store_dir, d)?),
+        };
+
+        Ok(NarInfo {
+            url: url.ok_or(NarInfoError::MissingField("URL"))?,
+            compression: match compression.as_deref() {
+                None | Some("") => Compression::Bzip2.into(),
+                Some(name) => NarCompression::from_name(name),
+            },
+            file_hash,
+            file_size,
+            nar_hash: nar_hash.ok_or(NarInfoError::MissingField("NarHash"))?,
+            nar_size: nar_size.ok_or(NarInfoError::MissingField("NarSize"))?,
+            references,
+            deriver,
+            sigs,
+            ca,
+            store_path,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NARINFO: &str = "\
+StorePath: /nix/store/xv2iccirbrvklck36f1g7vldn5v58vck-myfile
+URL: nar/1w1fff338fvdw53sqgamddn1b2xgds473pv6y13gizdbqjv4i5p3.nar.xz
+Compression: xz
+FileHash: sha256:1w1fff338fvdw53sqgamddn1b2xgds473pv6y13gizdbqjv4i5p3
+FileSize: 4092
+NarHash: sha256:1f1zyjj8pjba6hxq42ynvm59qm23wfqb7ff6b4p4qr5zp4jlsn2p
+NarSize: 18664
+References: a00d5f71k0vp5a6klkls0mvr1f7sx6ch-bar xv2iccirbrvklck36f1g7vldn5v58vck-myfile
+Deriver: a00d5f71k0vp5a6klkls0mvr1f7sx6ch-myfile.drv
+Sig: cache.example.org-1:AAECAw==
+CA: fixed:r:sha256:1f1zyjj8pjba6hxq42ynvm59qm23wfqb7ff6b4p4qr5zp4jlsn2p
+";
+
+    #[test]
+    fn round_trip() {
+        let info = NARINFO.parse::<NarInfo>().unwrap();
+        assert_eq!(info.store_path.name, "myfile");
+        assert_eq!(info.compression.known(), Some(Compression::Xz));
+        assert_eq!(info.file_size, Some(4092));
+        assert_eq!(info.nar_size, 18664);
+        assert_eq!(
+            info.references
+                .iter()
+                .map(|r| r.to_string())
+                .collect::<Vec<_>>(),
+            [
+                "/nix/store/a00d5f71k0vp5a6klkls0mvr1f7sx6ch-bar",
+                "/nix/store/xv2iccirbrvklck36f1g7vldn5v58vck-myfile"
+            ]
+        );
+        assert_eq!(info.deriver.as_ref().unwrap().name, "myfile.drv");
+        assert_eq!(
+            info.sigs,
+            [Signature {
+                key_name: "cache.example.org-1".into(),
+                sig: vec![0, 1, 2, 3],
+            }]
+        );
+        assert_eq!(
+            info.ca,
+            Some(ContentAddress::Fixed(
+                HashMode::Recursive,
+                info.nar_hash.clone()
+            ))
+        );
+        assert_eq!(info.to_string(), NARINFO);
+    }
+
+    #[test]
+    fn other_compressions() {
+        for name in ["br", "lz4", "lzma"] {
+            let narinfo = NARINFO.replace("Compression: xz", &format!("Compression: {name}"));
+            let info = narinfo.parse::<NarInfo>().unwrap();
+            assert_eq!(info.compression, NarCompression::Other(name.into()));
+            assert_eq!(info.compression.known(), None);
+            assert_eq!(info.to_string(), narinfo);
+        }
+
+        let info = NARINFO
+            .replace("Compression: xz", "Compression:")
+            .parse::<NarInfo>()
+            .unwrap();
+        assert_eq!(info.compression, Compression::Bzip2.into());
+    }
+
+    #[test]
+    fn nix_defaults() {
+        let info = "\
+StorePath: /nix/store/a00d5f71k0vp5a6klkls0mvr1f7sx6ch-bar
+URL: nar/a.nar.bz2
+NarHash: sha256-BAf3/1PqnvD/FpaI+b2YL9CExl7kTvRQCXr7VNkWoH0=
+NarSize: 8
+References:
+Deriver: unknown-deriver
+Unknown: field
+"
+        .parse::<NarInfo>()
+        .unwrap();
+        assert_eq!(info.compression, Compression::Bzip2.into());
+        assert_eq!(info.references, []);
+        assert_eq!(info.deriver, None);
+        // hashes are always written as base32
+        assert!(info
+            .to_string()
+            .contains("NarHash: sha256:0zd02vcm9yvs158g8kp4bv389l1gk2yzk24n2vzz17paagzzf1q4\n"));
+    }
+
+    #[test]
+    fn signatures() {
+        let mut info = NARINFO.parse::<NarInfo>().unwrap();
+        assert_eq!(
+            info.fingerprint(),
+            "1;/nix/store/xv2iccirbrvklck36f1g7vldn5v58vck-myfile;\
+             sha256:1f1zyjj8pjba6hxq42ynvm59qm23wfqb7ff6b4p4qr5zp4jlsn2p;18664;\
+             /nix/store/a00d5f71k0vp5a6klkls0mvr1f7sx6ch-bar,\
+             /nix/store/xv2iccirbrvklck36f1g7vldn5v58vck-myfile"
+        );
+
+        let key = SecretKey::generate("cache.example.org-1");
+        let other = SecretKey::generate("other-1");
+        // the old signature under that name is garbage
+        assert!(!info.is_signed_by(&[key.to_public()]));
+        info.sign(&key);
+        info.sign(&other);
+        assert_eq!(info.sigs.len(), 2);
+        assert!(info.is_signed_by(&[key.to_public()]));
+
+        let reparsed = info.to_string().parse::<NarInfo>().unwrap();
+        assert!(reparsed.is_signed_by(&[other.to_public()]));
+
+        info.nar_size += 1;
+        assert!(!info.is_signed_by(&[key.to_public(), other.to_public()]));
+    }
+
+    #[test]
+    fn unsorted_references() {
+        // the RFC 8032 key from the signing tests
+        let key = "test-1:nWGxne/9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2DXWpgBgrEKt9VL/tPJZAc6DuFy89qmIyWvAhpo9wdRGg=="
+            .parse::<SecretKey>()
+            .unwrap();
+        let sig = "test-1:ye4/HkH0eMhSJvDhMGxCRR8T+f24mGhVblCNgNwkhE4PRDJRjiWm4CJw+mFe6eWCQoMRK+YwNNsBAKy0hZZNAw==";
+
+        let unsorted = NARINFO
+            .replace(
+                "References: a00d5f71k0vp5a6klkls0mvr1f7sx6ch-bar xv2iccirbrvklck36f1g7vldn5v58vck-myfile",
+                "References: xv2iccirbrvklck36f1g7vldn5v58vck-myfile a00d5f71k0vp5a6klkls0mvr1f7sx6ch-bar",
+            )
+            .replace("Sig: cache.example.org-1:AAECAw==", &format!("Sig: {sig}"));
+        let mut info = unsorted.parse::<NarInfo>().unwrap();
+        assert_eq!(
+            info.fingerprint(),
+            NARINFO.parse::<NarInfo>().unwrap().fingerprint()
+        );
+        assert!(info.is_signed_by(&[key.to_public()]));
+
+        info.sigs.clear();
+        info.sign(&key);
+        assert_eq!(info.sigs[0].to_string(), sig);
+        // written back in the order they were read
+        assert_eq!(info.to_string(), unsorted);
+    }
+
+    #[test]
+    fn errors() {
+        let without = |field: &str| {
+            NARINFO
+                .lines()
+                .filter(|l| !l.starts_with(field))
+                .collect::<Vec<_>>()
+                .join("\n")
+                .parse::<NarInfo>()
+        };
+        assert_eq!(
+            without("NarHash"),
+            Err(NarInfoError::MissingField("NarHash"))
+        );
+        assert_eq!(without("URL"), Err(NarInfoError::MissingField("URL")));
+        assert_eq!(
+            format!("{NARINFO}NarSize: 1\n").parse::<NarInfo>(),
+            Err(NarInfoError::DuplicateField("NarSize"))
+        );
+        assert_eq!(
+            format!("{NARINFO}Compression: xz\n").parse::<NarInfo>(),
+            Err(NarInfoError::DuplicateField("Compression"))
+        );
+        assert_eq!(
+            "nonsense".parse::<NarInfo>(),
+            Err(NarInfoError::BadLine("nonsense".into()))
+        );
+    }
+}
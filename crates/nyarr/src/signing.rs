@@ -0,0 +1,190 @@
+//! Ed25519 keys in the `<name>:<base64>` form that `nix key generate-secret`
+//! and `nix key convert-secret-to-public` use, for signing binary caches.
+//!
+//! Secret keys are libsodium's: the 32 byte seed followed by the public key.
+
+use std::{fmt, str::FromStr};
+
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey, SECRET_KEY_LENGTH};
+use rand_core::OsRng;
+use thiserror::Error;
+
+use crate::narinfo::Signature;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum KeyError {
+    #[error("key is not of the form `name:base64`")]
+    BadFormat,
+    #[error("key {0:?} is not valid base64")]
+    BadBase64(String),
+    #[error("key {0:?} is not a valid ed25519 key")]
+    BadKey(String),
+}
+
+fn split_key(s: &str) -> Result<(&str, Vec<u8>), KeyError> {
+    match s.trim().split_once(':') {
+        Some((name, key)) if !name.is_empty() => Ok((
+            name,
+            base64::decode(key).map_err(|_| KeyError::BadBase64(name.to_string()))?,
+        )),
+        _ => Err(KeyError::BadFormat),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SecretKey {
+    pub name: String,
+    key: SigningKey,
+}
+
+impl SecretKey {
+    /// Makes a new key from the operating system's randomness.
+    pub fn generate(name: &str) -> SecretKey {
+        SecretKey {
+            name: name.to_string(),
+            key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    pub fn to_public(&self) -> PublicKey {
+        PublicKey {
+            name: self.name.clone(),
+            key: self.key.verifying_key(),
+        }
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Signature {
+        Signature {
+            key_name: self.name.clone(),
+            sig: self.key.sign(data).to_bytes().to_vec(),
+        }
+    }
+}
+
+impl fmt::Display for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}",
+            self.name,
+            base64::encode(self.key.to_keypair_bytes())
+        )
+    }
+}
+
+impl FromStr for SecretKey {
+    type Err = KeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, bytes) = split_key(s)?;
+        let bad = || KeyError::BadKey(name.to_string());
+        let bytes = <[u8; 2 * SECRET_KEY_LENGTH]>::try_from(bytes).map_err(|_| bad())?;
+        Ok(SecretKey {
+            name: name.to_string(),
+            // also checks that the public half belongs to the seed
+            key: SigningKey::from_keypair_bytes(&bytes).map_err(|_| bad())?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublicKey {
+    pub name: String,
+    key: VerifyingKey,
+}
+
+impl PublicKey {
+    /// Checks that `sig` is this key's signature of `data`. Signatures made
+    /// by keys of other names never verify.
+    pub fn verify(&self, data: &[u8], sig: &Signature) -> bool {
+        if sig.key_name != self.name {
+            return false;
+        }
+        match ed25519_dalek::Signature::from_slice(&sig.sig) {
+            Ok(sig) => self.key.verify_strict(data, &sig).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.name, base64::encode(self.key.as_bytes()))
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = KeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, bytes) = split_key(s)?;
+        let bad = || KeyError::BadKey(name.to_string());
+        let bytes = <[u8; 32]>::try_from(bytes).map_err(|_| bad())?;
+        Ok(PublicKey {
+            name: name.to_string(),
+            key: VerifyingKey::from_bytes(&bytes).map_err(|_| bad())?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The first test vector from RFC 8032, in the form Nix writes keys.
+    const SECRET: &str = "test-1:nWGxne/9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2DXWpgBgrEKt9VL/tPJZAc6DuFy89qmIyWvAhpo9wdRGg==";
+    const PUBLIC: &str = "test-1:11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+    const SIG: &str = "test-1:5VZDAMNgrHKQhuLMgG6CioSHfx645dl02HPgZSJJAVVfuIIVkKM7rMYeOXAc+bRr0lv18FlbviRlUUFDjnoQCw==";
+
+    #[test]
+    fn rfc8032() {
+        let secret = SECRET.parse::<SecretKey>().unwrap();
+        assert_eq!(secret.to_string(), SECRET);
+        assert_eq!(secret.to_public().to_string(), PUBLIC);
+
+        let sig = secret.sign(b"");
+        assert_eq!(sig.to_string(), SIG);
+        let public = PUBLIC.parse::<PublicKey>().unwrap();
+        assert!(public.verify(b"", &sig));
+        assert!(!public.verify(b"x", &sig));
+
+        let renamed = Signature {
+            key_name: "test-2".into(),
+            ..sig
+        };
+        assert!(!public.verify(b"", &renamed));
+    }
+
+    #[test]
+    fn generated() {
+        let secret = SecretKey::generate("cache-1");
+        let again = secret.to_string().parse::<SecretKey>().unwrap();
+        assert_eq!(again.to_public(), secret.to_public());
+        let public = secret.to_public().to_string().parse::<PublicKey>().unwrap();
+        assert!(public.verify(b"data", &again.sign(b"data")));
+    }
+
+    #[test]
+    fn bad_keys() {
+        assert_eq!("nocolon".parse::<PublicKey>(), Err(KeyError::BadFormat));
+        assert_eq!(":AAAA".parse::<PublicKey>(), Err(KeyError::BadFormat));
+        assert_eq!(
+            "k:!!".parse::<PublicKey>(),
+            Err(KeyError::BadBase64("k".into()))
+        );
+        // a public key is not a secret key
+        assert_eq!(
+            PUBLIC.parse::<SecretKey>().unwrap_err(),
+            KeyError::BadKey("test-1".into())
+        );
+        // nor is a seed with somebody else's public half
+        let mut mismatched = base64::decode(SECRET.split_once(':').unwrap().1).unwrap();
+        mismatched[63] ^= 1;
+        assert_eq!(
+            format!("k:{}", base64::encode(mismatched))
+                .parse::<SecretKey>()
+                .unwrap_err(),
+            KeyError::BadKey("k".into())
+        );
+    }
+}
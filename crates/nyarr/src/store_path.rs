@@ -0,0 +1,222 @@
+//! Computing where things land in the Nix store, without asking Nix.
+
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::hash::{flat_hash, from_nix_base32, to_nix_base32, Hash, HashAlgo, HashMode};
+
+pub const DEFAULT_STORE_DIR: &str = "/nix/store";
+
+/// Length of the hash part of a store path, in bytes.
+const DIGEST_LEN: usize = 20;
+
+/// Longest name Nix allows in a store path.
+const MAX_NAME_LEN: usize = 211;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum StorePathError {
+    #[error("invalid store path name {0:?}")]
+    InvalidName(String),
+    #[error("invalid store path {0:?}")]
+    InvalidPath(String),
+}
+
+/// A path in the Nix store, such as
+/// `/nix/store/a00d5f71k0vp5a6klkls0mvr1f7sx6ch-bar`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StorePath {
+    pub store_dir: String,
+    pub digest: [u8; DIGEST_LEN],
+    pub name: String,
+}
+
+impl StorePath {
+    /// Parses a whole path such as `/nix/store/<hash>-<name>`.
+    pub fn parse(path: &str) -> Result<StorePath, StorePathError> {
+        match path.rsplit_once('/') {
+            Some((store_dir, base_name)) if !store_dir.is_empty() => {
+                StorePath::from_base_name(store_dir, base_name)
+            }
+            _ => Err(StorePathError::InvalidPath(path.to_string())),
+        }
+    }
+
+    /// Parses `<hash>-<name>` as a path in `store_dir`, which is how narinfo
+    /// files refer to other paths.
+    pub fn from_base_name(store_dir: &str, base_name: &str) -> Result<StorePath, StorePathError> {
+        let invalid = || StorePathError::InvalidPath(base_name.to_string());
+        let (hash, name) = base_name.split_once('-').ok_or_else(invalid)?;
+        let digest = from_nix_base32(hash, DIGEST_LEN).ok_or_else(invalid)?;
+        check_name(name)?;
+        Ok(StorePath {
+            store_dir: store_dir.to_string(),
+            digest: digest.try_into().expect("decoded to DIGEST_LEN bytes"),
+            name: name.to_string(),
+        })
+    }
+
+    /// The base32 part of the path before the name.
+    pub fn hash_part(&self) -> String {
+        to_nix_base32(&self.digest)
+    }
+
+    /// The last component of the path, `<hash>-<name>`.
+    pub fn base_name(&self) -> String {
+        format!("{}-{}", self.hash_part(), self.name)
+    }
+}
+
+impl fmt::Display for StorePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.store_dir, self.base_name())
+    }
+}
+
+fn check_name(name: &str) -> Result<(), StorePathError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"+-._?=".contains(&c));
+    if valid {
+        Ok(())
+    } else {
+        Err(StorePathError::InvalidName(name.to_string()))
+    }
+}
+
+fn sha256(s: &str) -> Hash {
+    flat_hash(s.as_bytes(), HashAlgo::Sha256).expect("hashing a string cannot fail")
+}
+
+/// XORs a hash down to [`DIGEST_LEN`] bytes, as Nix's `compressHash`.
+fn compress_hash(hash: &Hash) -> [u8; DIGEST_LEN] {
+    let mut out = [0u8; DIGEST_LEN];
+    for (i, b) in hash.bytes.iter().enumerate() {
+        out[i % DIGEST_LEN] ^= b;
+    }
+    out
+}
+
+/// Equivalent to Nix's `makeStorePath`. `ty` is the kind of path, such as
+/// `source` or `output:out`.
+fn make_store_path(
+    store_dir: &str,
+    ty: &str,
+    hash: &Hash,
+    name: &str,
+) -> Result<StorePath, StorePathError> {
+    check_name(name)?;
+    let fingerprint = format!("{ty}:{}:{}:{store_dir}:{name}", hash.algo, hash.to_hex());
+    Ok(StorePath {
+        store_dir: store_dir.to_string(),
+        digest: compress_hash(&sha256(&fingerprint)),
+        name: name.to_string(),
+    })
+}
+
+/// Computes the store path of a fixed-output derivation, or of something added
+/// with `nix-store --add-fixed`, with content hash `hash` ingested as `mode`.
+///
+/// Recursive sha256 hashes get `source` paths, as `builtins.fetchTarball` and
+/// `nixpkgs.fetchzip` results do; everything else is hashed a second time with
+/// a `fixed:out:` fingerprint.
+pub fn fixed_output(
+    store_dir: &str,
+    name: &str,
+    hash: &Hash,
+    mode: HashMode,
+) -> Result<StorePath, StorePathError> {
+    if hash.algo == HashAlgo::Sha256 && mode == HashMode::Recursive {
+        make_store_path(store_dir, "source", hash, name)
+    } else {
+        let prefix = match mode {
+            HashMode::Recursive => "r:",
+            HashMode::Flat => "",
+        };
+        let inner = sha256(&format!(
+            "fixed:out:{prefix}{}:{}:",
+            hash.algo,
+            hash.to_hex()
+        ));
+        make_store_path(store_dir, "output:out", &inner, name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::NarHasher;
+
+    // These examples are from Nix Pill 18, "Nix Store Paths".
+
+    #[test]
+    fn flat() {
+        let hash = Hash::parse(
+            "f3f3c4763037e059b4d834eaf68595bbc02ba19f6d2a500dce06d124e2cd99bb",
+            Some(HashAlgo::Sha256),
+        )
+        .unwrap();
+        assert_eq!(
+            fixed_output(DEFAULT_STORE_DIR, "bar", &hash, HashMode::Flat)
+                .unwrap()
+                .to_string(),
+            "/nix/store/a00d5f71k0vp5a6klkls0mvr1f7sx6ch-bar"
+        );
+    }
+
+    #[test]
+    fn source() {
+        let hash = Hash::parse(
+            "sha256:2bfef67de873c54551d884fdab3055d84d573e654efa79db3c0d7b98883f9ee3",
+            None,
+        )
+        .unwrap();
+        let path = fixed_output(DEFAULT_STORE_DIR, "myfile", &hash, HashMode::Recursive).unwrap();
+        assert_eq!(
+            path.to_string(),
+            "/nix/store/xv2iccirbrvklck36f1g7vldn5v58vck-myfile"
+        );
+
+        let elsewhere = fixed_output("/other/store", "myfile", &hash, HashMode::Recursive).unwrap();
+        assert_eq!(elsewhere.store_dir, "/other/store");
+        assert_ne!(elsewhere.digest, path.digest);
+    }
+
+    #[test]
+    fn parse() {
+        let s = "/nix/store/a00d5f71k0vp5a6klkls0mvr1f7sx6ch-bar";
+        let path = StorePath::parse(s).unwrap();
+        assert_eq!(path.store_dir, DEFAULT_STORE_DIR);
+        assert_eq!(path.name, "bar");
+        assert_eq!(path.to_string(), s);
+
+        for bad in [
+            "a00d5f71k0vp5a6klkls0mvr1f7sx6ch-bar",
+            "/nix/store/a00d5f71k0vp5a6klkls0mvr1f7sx6c-bar",
+            "/nix/store/e00d5f71k0vp5a6klkls0mvr1f7sx6ch-bar",
+            "/nix/store/a00d5f71k0vp5a6klkls0mvr1f7sx6chbar",
+        ] {
+            assert_eq!(
+                StorePath::parse(bad),
+                Err(StorePathError::InvalidPath(
+                    bad.rsplit('/').next().unwrap().to_string()
+                )),
+                "{bad}"
+            );
+        }
+    }
+
+    #[test]
+    fn bad_names() {
+        let hash = NarHasher::new().finish();
+        for name in ["", ".hidden", "a/b", "spaces are bad"] {
+            assert_eq!(
+                fixed_output(DEFAULT_STORE_DIR, name, &hash, HashMode::Flat),
+                Err(StorePathError::InvalidName(name.to_string()))
+            );
+        }
+    }
+}
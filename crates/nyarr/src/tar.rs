@@ -2,99 +2,905 @@
 
 use std::{
     collections::BTreeMap,
-    io::{Read, Seek, Write},
+    fmt,
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
 };
 
-use crate::{io_error, unk_error, ConstByteStream, Directory, Executable, FileName, FsObject};
+use crate::{
+    io_error, unk_error, ByteStream, ConstByteStream, Directory, Executable, FileName, FsObject,
+    InvalidMember, PathComponent, SymlinkTarget, WriteResult,
+};
+use crate::{nar::valid_name, str, type_};
 use crate::{Error, Tar2NarError};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum StripRoot {
+    /// Drop the first component of every path, whatever the archive looks
+    /// like.
     StripRoot,
     DontStripRoot,
+    /// What Nix does when unpacking a tarball for `fetchTarball`: the archive
+    /// must have exactly one thing at the top level, which becomes the root.
+    /// Anything else is a [`Tar2NarError::NotSingleRoot`].
+    SingleRoot,
 }
 
-/// FIXME: maybe don't use a ConstByteStream since it forces reading the entire
-/// thing into memory. It's unclear how to do this with Seek, since the `tar`
-/// docs say that:
-///
-/// > "Note that care must be taken to consider each entry within
-/// > an archive in sequence. If entries are processed out of sequence (from what
-/// > the iterator returns), then the contents read for each entry may be
-/// > corrupted."
+/// A tar entry that has no NAR equivalent, such as a device node or FIFO.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnsupportedEntry {
+    pub path: String,
+    pub kind: String,
+}
+
+impl fmt::Display for UnsupportedEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} is a {}", self.path, self.kind)
+    }
+}
+
+/// What to do about [`UnsupportedEntry`]s. Nix does not put them in the NAR
+/// either way, but skipping them means the hash may not match what was
+/// expected.
+#[derive(Clone)]
+pub enum Strictness {
+    /// Fail the conversion.
+    Error,
+    /// Call the callback, then skip the entry.
+    Warn(Arc<dyn Fn(&UnsupportedEntry) + Send + Sync>),
+    /// Silently skip the entry.
+    Skip,
+}
+
+impl fmt::Debug for Strictness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Strictness::Error => f.write_str("Error"),
+            Strictness::Warn(_) => f.write_str("Warn(..)"),
+            Strictness::Skip => f.write_str("Skip"),
+        }
+    }
+}
+
+/// The choice between the kinds of [`Strictness`], as given on a command line:
+/// `error`, `warn` or `skip`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnsupportedEntries {
+    #[default]
+    Error,
+    Warn,
+    Skip,
+}
+
+impl UnsupportedEntries {
+    pub const NAMES: [&'static str; 3] = ["error", "warn", "skip"];
+
+    /// Makes the [`Strictness`], calling `warn` for each skipped entry if
+    /// this is [`UnsupportedEntries::Warn`].
+    pub fn strictness(
+        self,
+        warn: impl Fn(&UnsupportedEntry) + Send + Sync + 'static,
+    ) -> Strictness {
+        match self {
+            UnsupportedEntries::Error => Strictness::Error,
+            UnsupportedEntries::Warn => Strictness::Warn(Arc::new(warn)),
+            UnsupportedEntries::Skip => Strictness::Skip,
+        }
+    }
+}
+
+impl fmt::Display for UnsupportedEntries {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(Self::NAMES[*self as usize])
+    }
+}
+
+impl FromStr for UnsupportedEntries {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(UnsupportedEntries::Error),
+            "warn" => Ok(UnsupportedEntries::Warn),
+            "skip" => Ok(UnsupportedEntries::Skip),
+            _ => Err(format!(
+                "expected one of {}, got {s:?}",
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Tar2NarOptions {
+    pub strip_root: StripRoot,
+    pub strictness: Strictness,
+}
+
+impl Default for Tar2NarOptions {
+    fn default() -> Self {
+        Tar2NarOptions {
+            strip_root: StripRoot::DontStripRoot,
+            strictness: Strictness::Error,
+        }
+    }
+}
+
+/// Applies `strictness` to an entry of a type we cannot put in a nar.
+fn unsupported(
+    path: &[u8],
+    entry_type: tar::EntryType,
+    strictness: &Strictness,
+) -> Result<(), Tar2NarError> {
+    let unsupported = UnsupportedEntry {
+        path: String::from_utf8_lossy(path).into_owned(),
+        kind: entry_kind(entry_type),
+    };
+    match strictness {
+        Strictness::Error => return Err(Tar2NarError::Unsupported(unsupported)),
+        Strictness::Warn(warn) => warn(&unsupported),
+        Strictness::Skip => {}
+    }
+    Ok(())
+}
+
+fn entry_kind(entry_type: tar::EntryType) -> String {
+    match entry_type {
+        tar::EntryType::Char => "character device".into(),
+        tar::EntryType::Block => "block device".into(),
+        tar::EntryType::Fifo => "FIFO".into(),
+        other => format!("entry of unknown type {:?}", other.as_byte() as char),
+    }
+}
+
+/// Whether a regular member is executable. The streaming and tree paths both
+/// use this, so they cannot disagree.
+#[track_caller]
+fn executable(header: &tar::Header) -> Result<Executable, Tar2NarError> {
+    Ok(Executable::from_mode(header.mode().map_err(io_error)?))
+}
+
+/// Shares one seekable archive between the [`tar::Archive`] indexing it and
+/// the [`TarByteStream`]s that read from it afterwards.
 ///
-/// This seems to suggest that you can't random-read, sequential-write. Odd. So
-/// I guess we are reading it all into memory.
-pub fn tar_to_fsobject(
-    tar: impl Read + Seek,
-    strip_root: StripRoot,
-) -> Result<FsObject<ConstByteStream>, Tar2NarError> {
-    let mut archive = tar::Archive::new(tar);
+/// Positions are relative to `start`, since the `tar` crate gets confused by
+/// archives that do not start at the beginning of the reader.
+struct SharedReader<R> {
+    source: Arc<Mutex<R>>,
+    start: u64,
+}
+
+fn lock<R>(source: &Mutex<R>) -> io::Result<MutexGuard<'_, R>> {
+    source
+        .lock()
+        .map_err(|_| io::Error::other("tar reader mutex poisoned"))
+}
+
+impl<R: Read> Read for SharedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        lock(&self.source)?.read(buf)
+    }
+}
+
+impl<R: Seek> Seek for SharedReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => SeekFrom::Start(self.start + n),
+            other => other,
+        };
+        let new = lock(&self.source)?.seek(pos)?;
+        new.checked_sub(self.start).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to before the start of the archive",
+            )
+        })
+    }
+}
+
+#[derive(Clone)]
+enum Contents<R> {
+    /// Where the member's data is in the archive.
+    Lazy {
+        source: Arc<Mutex<R>>,
+        offset: u64,
+        len: u64,
+    },
+    /// GNU sparse files are not stored in one piece, so they are read up
+    /// front.
+    Buffered(ConstByteStream),
+}
+
+/// Contents of a tar member, which is only read out of the archive when it is
+/// serialised. The archive must not be modified in between.
+pub struct TarByteStream<R>(Contents<R>);
+
+// derive would want R: Clone
+impl<R> Clone for TarByteStream<R> {
+    fn clone(&self) -> Self {
+        TarByteStream(match &self.0 {
+            Contents::Lazy {
+                source,
+                offset,
+                len,
+            } => Contents::Lazy {
+                source: source.clone(),
+                offset: *offset,
+                len: *len,
+            },
+            Contents::Buffered(b) => Contents::Buffered(b.clone()),
+        })
+    }
+}
+
+impl<R: Read + Seek> ByteStream for TarByteStream<R> {
+    fn write_into(&self, w: &mut dyn Write) -> WriteResult {
+        match &self.0 {
+            Contents::Lazy {
+                source,
+                offset,
+                len,
+            } => {
+                let mut source = lock(source)?;
+                source.seek(SeekFrom::Start(*offset))?;
+                let copied = io::copy(&mut (&mut *source).take(*len), w)?;
+                if copied != *len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "tar archive ended in the middle of a file",
+                    ));
+                }
+                Ok(())
+            }
+            Contents::Buffered(b) => b.write_into(w),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match &self.0 {
+            Contents::Lazy { len, .. } => *len as usize,
+            Contents::Buffered(b) => b.len(),
+        }
+    }
+}
+
+/// Indexes a tar file into an [`FsObject`]. File contents stay in `tar` and
+/// are read back out of it when the tree is serialised, so only the tree
+/// itself is held in memory.
+pub fn tar_to_fsobject<R: Read + Seek>(
+    mut tar: R,
+    options: &Tar2NarOptions,
+) -> Result<FsObject<TarByteStream<R>>, Tar2NarError> {
+    // member positions are relative to wherever the archive starts
+    let start = tar.stream_position().map_err(io_error)?;
+    let source = Arc::new(Mutex::new(tar));
+    let strip_root = options.strip_root;
+    let mut archive = tar::Archive::new(SharedReader {
+        source: source.clone(),
+        start,
+    });
     let mut tree = Directory(BTreeMap::default());
 
     for member in archive.entries_with_seek().map_err(io_error)? {
         let mut member = member.map_err(io_error)?;
         let entry_type = member.header().entry_type();
 
-        let obj = if entry_type.is_dir() {
+        let obj = if entry_type.is_pax_global_extensions() {
+            // metadata about the archive, such as the commit ID GitHub puts in
+            continue;
+        } else if entry_type.is_dir() {
             FsObject::Directory(Directory::default())
-        } else if entry_type.is_file() {
-            let mut v = Vec::new();
-            member.read_to_end(&mut v).map_err(io_error)?;
+        } else if entry_type.is_file() || entry_type.is_contiguous() || entry_type.is_gnu_sparse() {
+            let contents = if entry_type.is_gnu_sparse() {
+                // the tar crate fills in the holes in sparse files for us
+                let mut v = Vec::new();
+                member.read_to_end(&mut v).map_err(io_error)?;
+                Contents::Buffered(ConstByteStream(v))
+            } else {
+                Contents::Lazy {
+                    source: source.clone(),
+                    offset: start + member.raw_file_position(),
+                    len: member.size(),
+                }
+            };
 
-            FsObject::File(
-                if member.header().mode().map_err(io_error)? & 0o111 != 0 {
-                    Executable::IsExecutable
-                } else {
-                    Executable::NotExecutable
-                },
-                ConstByteStream(v),
-            )
+            FsObject::File(executable(member.header())?, TarByteStream(contents))
         } else if entry_type.is_symlink() {
             let name = member
                 .link_name_bytes()
                 .ok_or("empty link name")
                 .map_err(|e| unk_error(e))?;
-            FsObject::Symlink(FileName::try_from(name.as_ref()).map_err(unk_error)?)
+            FsObject::Symlink(SymlinkTarget::from(name.as_ref()))
+        } else if entry_type.is_hard_link() {
+            let target = member
+                .link_name_bytes()
+                .ok_or("empty link name")
+                .map_err(unk_error)?;
+            resolve_hard_link(
+                &tree,
+                member.path_bytes().as_ref(),
+                target.as_ref(),
+                strip_root,
+            )?
         } else {
-            // idk what that is, let's skip it
+            unsupported(&member.path_bytes(), entry_type, &options.strictness)?;
             continue;
         };
 
-        let name = FileName::try_from(member.path_bytes().as_ref());
-
-        if let Err(_) = name {
-            // it's just a ./ entry. we can ignore it.
-        } else if let Ok(name) = name {
-            // FIXME: ugly code
-            let name = if strip_root == StripRoot::StripRoot {
-                if let Some(n) = name.drop_first() {
-                    n
-                } else {
-                    continue;
-                }
-            } else {
-                name
-            };
+        insert_member(&mut tree, member.path_bytes().as_ref(), obj, strip_root)?;
+    }
 
-            tree.insert(&name, obj).map_err(unk_error)?;
-        }
+    finish_tree(tree, strip_root)
+}
+
+/// Works out where an archive member found at `path` goes in the tree,
+/// applying `strip_root`. Returns `None` for the root itself.
+fn member_name(path: &[u8], strip_root: StripRoot) -> Result<Option<FileName>, Tar2NarError> {
+    // if this fails, it's just a ./ entry. we can ignore it.
+    let Ok(name) = FileName::try_from(path) else {
+        return Ok(None);
+    };
+
+    let problem = if name.0.iter().any(|c| c == b"..") {
+        Some(InvalidMember::ParentDir)
+    } else if path.contains(&0) {
+        Some(InvalidMember::Nul)
+    } else {
+        None
+    };
+    if let Some(problem) = problem {
+        return Err(invalid_member(path, problem));
+    }
+
+    Ok(match strip_root {
+        StripRoot::StripRoot => name.drop_first(),
+        StripRoot::DontStripRoot | StripRoot::SingleRoot => Some(name),
+    })
+}
+
+fn invalid_member(path: &[u8], problem: InvalidMember) -> Tar2NarError {
+    Tar2NarError::InvalidMember {
+        path: String::from_utf8_lossy(path).into_owned(),
+        problem,
+    }
+}
+
+/// Inserts an archive member found at `path` into `tree`, applying
+/// `strip_root`.
+pub(crate) fn insert_member<T: ByteStream>(
+    tree: &mut Directory<T>,
+    path: &[u8],
+    obj: FsObject<T>,
+    strip_root: StripRoot,
+) -> Result<(), Tar2NarError> {
+    match member_name(path, strip_root)? {
+        Some(name) => tree
+            .insert(&name, obj)
+            .map_err(|problem| invalid_member(path, problem)),
+        None => Ok(()),
+    }
+}
+
+/// Turns the tree of everything in an archive into the object it stands for,
+/// enforcing [`StripRoot::SingleRoot`] if asked to.
+pub(crate) fn finish_tree<T: ByteStream>(
+    tree: Directory<T>,
+    strip_root: StripRoot,
+) -> Result<FsObject<T>, Tar2NarError> {
+    if strip_root != StripRoot::SingleRoot {
+        return Ok(FsObject::Directory(tree));
     }
 
-    Ok(FsObject::Directory(tree))
+    if tree.0.len() == 1 {
+        let (_, root) = tree.0.into_iter().next().expect("just checked the length");
+        Ok(*root)
+    } else {
+        Err(Tar2NarError::NotSingleRoot {
+            roots: tree
+                .0
+                .keys()
+                .map(|k| String::from_utf8_lossy(k).into_owned())
+                .collect(),
+        })
+    }
+}
+
+/// Hard links are turned into copies of what they point to, which is what
+/// Nix ends up with after unpacking and dumping. The target has to come
+/// earlier in the archive, as tar always writes them.
+fn resolve_hard_link<T: ByteStream + Clone>(
+    tree: &Directory<T>,
+    link: &[u8],
+    target: &[u8],
+    strip_root: StripRoot,
+) -> Result<FsObject<T>, Tar2NarError> {
+    match member_name(target, strip_root)?.and_then(|t| tree.get(&t)) {
+        Some(FsObject::File(exec, contents)) => Ok(FsObject::File(*exec, contents.clone())),
+        Some(FsObject::Symlink(to)) => Ok(FsObject::Symlink(to.clone())),
+        _ => Err(Tar2NarError::MissingLinkTarget {
+            link: String::from_utf8_lossy(link).into_owned(),
+            target: String::from_utf8_lossy(target).into_owned(),
+        }),
+    }
 }
 
 pub fn tar_to_nar(
     tar: impl Read + Seek,
     mut into: impl Write,
-    strip_root: StripRoot,
+    options: &Tar2NarOptions,
 ) -> Result<(), Error> {
-    let fso = tar_to_fsobject(tar, strip_root)?;
+    let fso = tar_to_fsobject(tar, options)?;
 
     fso.serialise_toplevel(&mut into)?;
     Ok(())
 }
 
+/// Outcome of [`stream_tar_to_nar`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Streamed {
+    Done,
+    /// The member at `path` could not be written without going back, because
+    /// it is out of nar order or is a hard link. Whatever was written so far
+    /// has to be thrown away.
+    NotStreamable {
+        path: String,
+    },
+}
+
+enum StreamedMember {
+    Directory,
+    File(Executable),
+    Symlink(Vec<u8>),
+}
+
+enum Entered {
+    /// An entry has been started and the node goes next.
+    Started,
+    /// The path is a directory that is open already, which happens when it is
+    /// listed after some of its contents.
+    AlreadyOpen,
+    /// The path sorts before something that has already been written.
+    OutOfOrder,
+}
+
+struct OpenDirectory {
+    name: PathComponent,
+    last_child: Option<PathComponent>,
+}
+
+/// Writes nar tokens as members come out of a tar, keeping track of which
+/// directories are still open.
+struct NarStreamWriter<W> {
+    w: W,
+    /// Directories that have not been closed yet, starting with the root.
+    open: Vec<OpenDirectory>,
+    /// Set once a root that is not a directory has been written.
+    finished: bool,
+}
+
+impl<W: Write> NarStreamWriter<W> {
+    fn start_root_directory(&mut self) -> WriteResult {
+        if self.open.is_empty() {
+            str(b"nix-archive-1", &mut self.w)?;
+            str(b"(", &mut self.w)?;
+            type_(b"directory", &mut self.w)?;
+            self.open.push(OpenDirectory {
+                name: Vec::new(),
+                last_child: None,
+            });
+        }
+        Ok(())
+    }
+
+    fn close_directory(&mut self) -> WriteResult {
+        self.open.pop();
+        str(b")", &mut self.w)?;
+        if !self.open.is_empty() {
+            // the entry this directory was in
+            str(b")", &mut self.w)?;
+        }
+        Ok(())
+    }
+
+    /// Starts an entry called `name` in the innermost open directory, unless
+    /// it does not sort after everything already in there.
+    fn start_entry(&mut self, name: &[u8]) -> Result<bool, io::Error> {
+        let dir = self.open.last_mut().expect("the root is open");
+        if dir.last_child.as_deref().is_some_and(|last| last >= name) {
+            return Ok(false);
+        }
+        dir.last_child = Some(name.to_vec());
+
+        str(b"entry", &mut self.w)?;
+        str(b"(", &mut self.w)?;
+        str(b"name", &mut self.w)?;
+        str(name, &mut self.w)?;
+        str(b"node", &mut self.w)?;
+        Ok(true)
+    }
+
+    /// Closes and opens directories so that `path` can be written next.
+    fn enter(&mut self, path: &[PathComponent]) -> Result<Entered, io::Error> {
+        let open_names = || self.open[1..].iter().map(|d| &d.name);
+        if path.len() < self.open.len() && open_names().zip(path).all(|(a, b)| a == b) {
+            return Ok(Entered::AlreadyOpen);
+        }
+
+        let (leaf, parents) = path.split_last().expect("paths are not empty");
+        let common = open_names()
+            .zip(parents)
+            .take_while(|(a, b)| a == b)
+            .count();
+        while self.open.len() > common + 1 {
+            self.close_directory()?;
+        }
+        for dir in &parents[common..] {
+            if !self.start_entry(dir)? {
+                return Ok(Entered::OutOfOrder);
+            }
+            self.open_directory(dir)?;
+        }
+        Ok(if self.start_entry(leaf)? {
+            Entered::Started
+        } else {
+            Entered::OutOfOrder
+        })
+    }
+
+    fn open_directory(&mut self, name: &[u8]) -> WriteResult {
+        str(b"(", &mut self.w)?;
+        type_(b"directory", &mut self.w)?;
+        self.open.push(OpenDirectory {
+            name: name.to_vec(),
+            last_child: None,
+        });
+        Ok(())
+    }
+
+    /// Writes a node for a member that is not a directory, from its opening
+    /// paren up to its closing one.
+    fn write_leaf(
+        &mut self,
+        member: &StreamedMember,
+        contents: impl Read,
+        len: u64,
+    ) -> WriteResult {
+        str(b"(", &mut self.w)?;
+        match member {
+            StreamedMember::File(exec) => {
+                type_(b"regular", &mut self.w)?;
+                if *exec == Executable::IsExecutable {
+                    str(b"executable", &mut self.w)?;
+                    str(b"", &mut self.w)?;
+                }
+                str(b"contents", &mut self.w)?;
+                self.w.write_all(&len.to_le_bytes())?;
+                let copied = io::copy(&mut contents.take(len), &mut self.w)?;
+                if copied != len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "tar archive ended in the middle of a file",
+                    ));
+                }
+                let padding = (8 - len % 8) % 8;
+                self.w.write_all(&[0u8; 8][..padding as usize])?;
+            }
+            StreamedMember::Symlink(target) => {
+                type_(b"symlink", &mut self.w)?;
+                str(b"target", &mut self.w)?;
+                str(target, &mut self.w)?;
+            }
+            StreamedMember::Directory => unreachable!("directories are opened, not written"),
+        }
+        str(b")", &mut self.w)
+    }
+}
+
+/// Writes a nar while reading `tar` from front to back, so it does not need
+/// [`Seek`] and never holds file contents in memory. This only works if the
+/// archive is already in nar order, with names sorted bytewise and nothing
+/// coming back to a directory once something after it has been seen. Archives
+/// made by `git archive` usually are, apart from names like `foo.txt` sorting
+/// before a directory `foo`.
+///
+/// Hard links and GNU sparse files are not streamable either, since their
+/// contents are not where the member is.
+pub fn stream_tar_to_nar(
+    tar: impl Read,
+    into: impl Write,
+    options: &Tar2NarOptions,
+) -> Result<Streamed, Tar2NarError> {
+    let mut archive = tar::Archive::new(tar);
+    let mut out = NarStreamWriter {
+        w: into,
+        open: Vec::new(),
+        finished: false,
+    };
+    let mut single_root = None;
+
+    for member in archive.entries().map_err(io_error)? {
+        let mut member = member.map_err(io_error)?;
+        let entry_type = member.header().entry_type();
+        let path = member.path_bytes().into_owned();
+        let not_streamable = || {
+            Ok(Streamed::NotStreamable {
+                path: String::from_utf8_lossy(&path).into_owned(),
+            })
+        };
+
+        let kind = if entry_type.is_pax_global_extensions() {
+            continue;
+        } else if entry_type.is_dir() {
+            StreamedMember::Directory
+        } else if entry_type.is_file() || entry_type.is_contiguous() {
+            StreamedMember::File(executable(member.header())?)
+        } else if entry_type.is_symlink() {
+            let target = member
+                .link_name_bytes()
+                .ok_or("empty link name")
+                .map_err(unk_error)?;
+            StreamedMember::Symlink(target.into_owned())
+        } else if entry_type.is_hard_link() || entry_type.is_gnu_sparse() {
+            return not_streamable();
+        } else {
+            unsupported(&path, entry_type, &options.strictness)?;
+            continue;
+        };
+
+        let Some(name) = member_name(&path, options.strip_root)? else {
+            continue;
+        };
+        let mut components = name.0;
+        if options.strip_root == StripRoot::SingleRoot {
+            let top = components.remove(0);
+            match &single_root {
+                None => single_root = Some(top),
+                Some(root) if *root == top => {}
+                Some(_) => return not_streamable(),
+            }
+        }
+        if out.finished {
+            return not_streamable();
+        }
+
+        let is_dir = matches!(kind, StreamedMember::Directory);
+        let len = member.size();
+        if components.is_empty() {
+            // the root itself, with StripRoot::SingleRoot
+            if is_dir {
+                out.start_root_directory().map_err(io_error)?;
+            } else if out.open.is_empty() {
+                str(b"nix-archive-1", &mut out.w).map_err(io_error)?;
+                out.write_leaf(&kind, &mut member, len).map_err(io_error)?;
+                out.finished = true;
+            } else {
+                return not_streamable();
+            }
+            continue;
+        }
+
+        out.start_root_directory().map_err(io_error)?;
+        match out.enter(&components).map_err(io_error)? {
+            Entered::Started => {}
+            Entered::AlreadyOpen if is_dir => continue,
+            Entered::AlreadyOpen | Entered::OutOfOrder => return not_streamable(),
+        }
+        if is_dir {
+            out.open_directory(components.last().expect("not empty"))
+                .map_err(io_error)?;
+        } else {
+            out.write_leaf(&kind, &mut member, len).map_err(io_error)?;
+            str(b")", &mut out.w).map_err(io_error)?;
+        }
+    }
+
+    if !out.finished {
+        if options.strip_root == StripRoot::SingleRoot && out.open.is_empty() {
+            // the tree builder has a better error for this
+            return Ok(Streamed::NotStreamable {
+                path: String::new(),
+            });
+        }
+        out.start_root_directory().map_err(io_error)?;
+        while !out.open.is_empty() {
+            out.close_directory().map_err(io_error)?;
+        }
+    }
+    Ok(Streamed::Done)
+}
+
+/// [`stream_tar_to_nar`], falling back to the tree builder behind
+/// [`tar_to_nar`] for archives that cannot be streamed.
+///
+/// `open` is called to read the archive, and again if it has to fall back
+/// with [`spooled_tar_to_nar`]. Likewise
+/// `output` makes somewhere to write the nar, and is called again when falling
+/// back, after the first writer has been dropped; the writer that is returned
+/// has the whole nar in it. Warnings about unsupported entries may be given
+/// twice when falling back.
+pub fn tar_to_nar_streaming<R: Read, W: Write>(
+    mut open: impl FnMut() -> io::Result<R>,
+    mut output: impl FnMut() -> W,
+    options: &Tar2NarOptions,
+) -> Result<W, Error> {
+    let mut w = output();
+    if stream_tar_to_nar(open()?, &mut w, options)? == Streamed::Done {
+        return Ok(w);
+    }
+    drop(w);
+
+    let mut w = output();
+    spooled_tar_to_nar(open()?, &mut w, options)?;
+    Ok(w)
+}
+
+/// [`tar_to_nar`] for archives that cannot [`Seek`], which are copied into a
+/// temporary file first rather than into memory.
+pub fn spooled_tar_to_nar(
+    mut tar: impl Read,
+    into: impl Write,
+    options: &Tar2NarOptions,
+) -> Result<(), Error> {
+    let mut spool = tempfile::tempfile()?;
+    io::copy(&mut tar, &mut spool)?;
+    spool.rewind()?;
+    tar_to_nar(BufReader::new(spool), into, options)
+}
+
+/// Modification time of everything [`fsobject_to_tar`] writes, which is what
+/// files in the Nix store have.
+pub const TAR_MTIME: u64 = 1;
+
+const BLOCK_SIZE: u64 = 512;
+
+/// Writes tar entries with exactly the names and link targets given. The
+/// `tar` crate's builder normalises link targets, which would change the nar
+/// they turn back into.
+struct TarWriter<W> {
+    w: W,
+}
+
+impl<W: Write> TarWriter<W> {
+    fn pad(&mut self, len: u64) -> WriteResult {
+        let padding = (BLOCK_SIZE - len % BLOCK_SIZE) % BLOCK_SIZE;
+        self.w
+            .write_all(&[0u8; BLOCK_SIZE as usize][..padding as usize])
+    }
+
+    /// Writes a GNU `././@LongLink` record for a name that does not fit in the
+    /// header.
+    fn long_name(&mut self, kind: u8, name: &[u8]) -> WriteResult {
+        let mut header = tar::Header::new_gnu();
+        header.as_gnu_mut().expect("gnu header").name[..13].copy_from_slice(b"././@LongLink");
+        header.set_entry_type(tar::EntryType::new(kind));
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(TAR_MTIME);
+        header.set_size(name.len() as u64 + 1);
+        header.set_cksum();
+        self.w.write_all(header.as_bytes())?;
+        self.w.write_all(name)?;
+        self.w.write_all(&[0])?;
+        self.pad(name.len() as u64 + 1)
+    }
+
+    fn header(
+        &mut self,
+        path: &[u8],
+        entry_type: tar::EntryType,
+        mode: u32,
+        size: u64,
+        link: Option<&[u8]>,
+    ) -> WriteResult {
+        let mut header = tar::Header::new_gnu();
+        let gnu = header.as_gnu_mut().expect("gnu header");
+
+        // names that exactly fill the field are fine without a terminator
+        if path.len() <= gnu.name.len() {
+            gnu.name[..path.len()].copy_from_slice(path);
+        } else {
+            self.long_name(b'L', path)?;
+            let n = gnu.name.len();
+            gnu.name.copy_from_slice(&path[..n]);
+        }
+        if let Some(link) = link {
+            if link.len() <= gnu.linkname.len() {
+                gnu.linkname[..link.len()].copy_from_slice(link);
+            } else {
+                self.long_name(b'K', link)?;
+                let n = gnu.linkname.len();
+                gnu.linkname.copy_from_slice(&link[..n]);
+            }
+        }
+
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(TAR_MTIME);
+        header.set_size(size);
+        header.set_cksum();
+        self.w.write_all(header.as_bytes())
+    }
+
+    fn write<T: ByteStream>(&mut self, path: &mut Vec<u8>, obj: &FsObject<T>) -> WriteResult {
+        match obj {
+            FsObject::File(exec, contents) => {
+                let mode = match exec {
+                    Executable::IsExecutable => 0o755,
+                    Executable::NotExecutable => 0o644,
+                };
+                let len = contents.len() as u64;
+                self.header(path, tar::EntryType::Regular, mode, len, None)?;
+                contents.write_into(&mut self.w)?;
+                self.pad(len)?;
+            }
+            FsObject::Symlink(target) => {
+                self.header(
+                    path,
+                    tar::EntryType::Symlink,
+                    0o777,
+                    0,
+                    Some(target.as_bytes()),
+                )?;
+            }
+            FsObject::Directory(entries) => {
+                if !path.is_empty() {
+                    path.push(b'/');
+                    self.header(path, tar::EntryType::Directory, 0o755, 0, None)?;
+                }
+                let len = path.len();
+                for (name, child) in &entries.0 {
+                    path.extend_from_slice(name);
+                    self.write(path, child)?;
+                    path.truncate(len);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes `fso` out as a tar that turns back into the same nar, and is the
+/// same every time: entries are in nar order, everything belongs to uid and
+/// gid 0 with an mtime of [`TAR_MTIME`], files are 0644 or 0755 and
+/// directories 0755.
+///
+/// With a `root` name, `fso` is put in the archive under that name, which
+/// [`StripRoot::SingleRoot`] undoes. Otherwise `fso` has to be a directory and
+/// its contents go at the top level of the archive, as with
+/// [`StripRoot::DontStripRoot`].
+pub fn fsobject_to_tar<T: ByteStream>(
+    fso: &FsObject<T>,
+    root: Option<&[u8]>,
+    into: impl Write,
+) -> Result<(), Tar2NarError> {
+    let mut path = match root {
+        Some(root) if valid_name(root) => root.to_vec(),
+        Some(root) => {
+            return Err(unk_error(format!(
+                "invalid root name {:?}",
+                String::from_utf8_lossy(root)
+            )))
+        }
+        None if matches!(fso, FsObject::Directory(_)) => Vec::new(),
+        None => {
+            return Err(unk_error(
+                "only a directory can be written without a root name",
+            ))
+        }
+    };
+
+    let mut out = TarWriter { w: into };
+    out.write(&mut path, fso).map_err(io_error)?;
+    // end of archive marker
+    out.w
+        .write_all(&[0u8; 2 * BLOCK_SIZE as usize])
+        .map_err(io_error)
+}
+
 #[cfg(test)]
 mod tests {
     use crate::tests::basic_tree;
@@ -102,10 +908,18 @@ mod tests {
     use super::*;
     use std::io::Cursor;
 
+    /// [`tar_to_fsobject`] with everything read out, so it can be compared.
+    fn buffered(
+        tar: impl Read + Seek,
+        options: &Tar2NarOptions,
+    ) -> Result<FsObject<ConstByteStream>, Tar2NarError> {
+        Ok(tar_to_fsobject(tar, options)?.to_buffered().unwrap())
+    }
+
     #[test]
     fn basic_tar() {
         let mut tarfile = Cursor::new(include_bytes!("testdata/test1.tar"));
-        let fso = tar_to_fsobject(&mut tarfile, StripRoot::DontStripRoot).unwrap();
+        let fso = buffered(&mut tarfile, &Tar2NarOptions::default()).unwrap();
         assert_eq!(fso, basic_tree());
     }
 
@@ -115,7 +929,460 @@ mod tests {
         let expected = include_bytes!("testdata/test1.nar");
 
         let mut nar = Vec::new();
-        tar_to_nar(&mut tarfile, &mut nar, StripRoot::DontStripRoot).unwrap();
+        tar_to_nar(&mut tarfile, &mut nar, &Tar2NarOptions::default()).unwrap();
         assert_eq!(nar, expected);
     }
+
+    #[test]
+    fn reads_lazily_from_offset() {
+        // the archive does not have to start at the beginning of the reader
+        let mut data = b"junk".to_vec();
+        data.extend_from_slice(include_bytes!("testdata/test1.tar"));
+        let mut tarfile = Cursor::new(data);
+        tarfile.set_position(4);
+
+        let mut nar = Vec::new();
+        tar_to_nar(tarfile, &mut nar, &Tar2NarOptions::default()).unwrap();
+        assert_eq!(nar, include_bytes!("testdata/test1.nar"));
+    }
+
+    #[test]
+    fn raw_symlink_targets() {
+        let mut tarfile = Cursor::new(include_bytes!("testdata/links.tar"));
+        let fso = buffered(&mut tarfile, &strip_root()).unwrap();
+        let FsObject::Directory(dir) = &fso else {
+            panic!("expected a directory, got {fso:?}");
+        };
+        for (name, target) in [
+            (&b"abs"[..], &b"/usr/bin/env"[..]),
+            (b"dot", b"./foo"),
+            (b"double", b"a//b"),
+            (b"slash", b"foo/"),
+            (b"up", b"../foo"),
+        ] {
+            assert_eq!(
+                dir.get(&FileName::singleton(name.to_vec())),
+                Some(&FsObject::Symlink(SymlinkTarget::from(target)))
+            );
+        }
+
+        let mut tarfile = Cursor::new(include_bytes!("testdata/links.tar"));
+        let mut nar = Vec::new();
+        tar_to_nar(&mut tarfile, &mut nar, &strip_root()).unwrap();
+        assert_eq!(nar, include_bytes!("testdata/links.nar"));
+    }
+
+    /// Builds a tar of files, or directories for paths ending in `/`. Names
+    /// are written as is, without the checks `tar::Builder` would do.
+    fn files_tar(paths: &[&str]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for path in paths {
+            let mut header = tar::Header::new_gnu();
+            let data = if path.ends_with('/') {
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_mode(0o755);
+                &b""[..]
+            } else {
+                header.set_mode(0o644);
+                &b"aaa\n"[..]
+            };
+            header.set_size(data.len() as u64);
+            header.as_gnu_mut().unwrap().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn invalid_members() {
+        for (paths, path, problem) in [
+            (&["root/../x"][..], "root/../x", InvalidMember::ParentDir),
+            (&["a", "a"], "a", InvalidMember::Duplicate),
+            (&["a", "a/x"], "a/x", InvalidMember::Conflict),
+            (&["a/x", "a"], "a", InvalidMember::Conflict),
+            (&["a/x/", "a/x"], "a/x", InvalidMember::Conflict),
+        ] {
+            match buffered(Cursor::new(files_tar(paths)), &Default::default()) {
+                Err(Tar2NarError::InvalidMember {
+                    path: got_path,
+                    problem: got_problem,
+                }) => assert_eq!((got_path.as_str(), got_problem), (path, problem)),
+                other => panic!("expected an error for {paths:?}, got {other:?}"),
+            }
+        }
+
+        let mut tree = Directory::<ConstByteStream>::default();
+        assert!(matches!(
+            insert_member(
+                &mut tree,
+                b"a\0b",
+                FsObject::Directory(Directory::default()),
+                StripRoot::DontStripRoot
+            ),
+            Err(Tar2NarError::InvalidMember {
+                problem: InvalidMember::Nul,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn directories_merge() {
+        let fso = buffered(
+            Cursor::new(files_tar(&["a/", "a/x", "a/", "./", "a/y/", "a/y/z"])),
+            &Default::default(),
+        )
+        .unwrap();
+        let file = || {
+            Box::new(FsObject::File(
+                Executable::NotExecutable,
+                ConstByteStream(b"aaa\n".to_vec()),
+            ))
+        };
+        let y = Directory(BTreeMap::from([(b"z".to_vec(), file())]));
+        let a = Directory(BTreeMap::from([
+            (b"x".to_vec(), file()),
+            (b"y".to_vec(), Box::new(FsObject::Directory(y))),
+        ]));
+        assert_eq!(
+            fso,
+            FsObject::Directory(Directory(BTreeMap::from([(
+                b"a".to_vec(),
+                Box::new(FsObject::Directory(a))
+            )])))
+        );
+    }
+
+    #[test]
+    fn single_root() {
+        let single = Tar2NarOptions {
+            strip_root: StripRoot::SingleRoot,
+            ..Default::default()
+        };
+        let tar = include_bytes!("testdata/links.tar");
+        assert_eq!(
+            buffered(Cursor::new(tar), &single).unwrap(),
+            buffered(Cursor::new(tar), &strip_root()).unwrap()
+        );
+
+        assert_eq!(
+            buffered(Cursor::new(files_tar(&["./f"])), &single).unwrap(),
+            FsObject::File(
+                Executable::NotExecutable,
+                ConstByteStream(b"aaa\n".to_vec())
+            )
+        );
+
+        for (paths, roots) in [
+            (&["a/x", "b"][..], &["a", "b"][..]),
+            (&["a/x", "b/y"], &["a", "b"]),
+            (&[], &[]),
+        ] {
+            match buffered(Cursor::new(files_tar(paths)), &single) {
+                Err(Tar2NarError::NotSingleRoot { roots: got }) => assert_eq!(got, roots),
+                other => panic!("expected an error for {paths:?}, got {other:?}"),
+            }
+        }
+    }
+
+    fn hard_link_tar(target: &str) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o755);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "root/f", &b"aaa\n"[..])
+            .unwrap();
+
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        builder.append_link(&mut header, "root/g", target).unwrap();
+
+        builder.into_inner().unwrap()
+    }
+
+    fn strip_root() -> Tar2NarOptions {
+        Tar2NarOptions {
+            strip_root: StripRoot::StripRoot,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn hard_links() {
+        let fso = buffered(Cursor::new(hard_link_tar("root/f")), &strip_root()).unwrap();
+        let file = || {
+            Box::new(FsObject::File(
+                Executable::IsExecutable,
+                ConstByteStream(b"aaa\n".to_vec()),
+            ))
+        };
+        assert_eq!(
+            fso,
+            FsObject::Directory(Directory(BTreeMap::from([
+                (b"f".to_vec(), file()),
+                (b"g".to_vec(), file()),
+            ])))
+        );
+
+        assert!(matches!(
+            buffered(Cursor::new(hard_link_tar("root/nope")), &strip_root()),
+            Err(Tar2NarError::MissingLinkTarget { .. })
+        ));
+    }
+
+    fn fifo_tar() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::XGlobalHeader);
+        header.set_size(0);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "pax_global_header", &b""[..])
+            .unwrap();
+
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Fifo);
+        header.set_size(0);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "root/p", &b""[..])
+            .unwrap();
+
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn unsupported_entries() {
+        let tar = fifo_tar();
+        let with = |strictness| Tar2NarOptions {
+            strip_root: StripRoot::StripRoot,
+            strictness,
+        };
+        let empty = FsObject::Directory(Directory::default());
+
+        assert_eq!(
+            buffered(Cursor::new(&tar), &with(Strictness::Skip)).unwrap(),
+            empty
+        );
+
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen2 = seen.clone();
+        let warn = UnsupportedEntries::Warn
+            .strictness(move |e: &UnsupportedEntry| seen2.lock().unwrap().push(e.clone()));
+        assert_eq!(buffered(Cursor::new(&tar), &with(warn)).unwrap(), empty);
+        let fifo = UnsupportedEntry {
+            path: "root/p".into(),
+            kind: "FIFO".into(),
+        };
+        assert_eq!(seen.lock().unwrap().as_slice(), std::slice::from_ref(&fifo));
+
+        match buffered(Cursor::new(&tar), &with(Strictness::Error)) {
+            Err(Tar2NarError::Unsupported(e)) => assert_eq!(e, fifo),
+            other => panic!("expected an error, got {:?}", other),
+        }
+        // as it is by default
+        assert!(matches!(
+            buffered(Cursor::new(&tar), &Default::default()),
+            Err(Tar2NarError::Unsupported(_))
+        ));
+
+        for name in UnsupportedEntries::NAMES {
+            assert_eq!(
+                name.parse::<UnsupportedEntries>().unwrap().to_string(),
+                name
+            );
+        }
+        assert!("ignore".parse::<UnsupportedEntries>().is_err());
+    }
+
+    /// Streams `tar`, checking that the result is what the tree builder gives.
+    fn stream(tar: &[u8], options: &Tar2NarOptions) -> Streamed {
+        let mut expected = Vec::new();
+        tar_to_nar(Cursor::new(tar), &mut expected, options).unwrap();
+
+        let mut nar = Vec::new();
+        let streamed = stream_tar_to_nar(tar, &mut nar, options).unwrap();
+        let fallback = tar_to_nar_streaming(|| Ok(tar), Vec::new, options).unwrap();
+        assert_eq!(fallback, expected);
+        if streamed == Streamed::Done {
+            assert_eq!(nar, expected);
+        }
+        streamed
+    }
+
+    #[test]
+    fn streams_sorted_archives() {
+        let default = Tar2NarOptions::default();
+        let single = Tar2NarOptions {
+            strip_root: StripRoot::SingleRoot,
+            ..Default::default()
+        };
+        for (paths, options) in [
+            (&["a/", "a/x", "a/y/", "a/y/z", "b"][..], &default),
+            (&["a/x", "b/c/d", "b/e"], &default),
+            (&["a/x", "a/", "a/y"], &default),
+            (&[], &default),
+            (&["root/", "root/a", "root/b/c"], &strip_root()),
+            (&["root/", "root/a", "root/b/c"], &single),
+            (&["./f"], &single),
+        ] {
+            assert_eq!(
+                stream(&files_tar(paths), options),
+                Streamed::Done,
+                "{paths:?}"
+            );
+        }
+        assert_eq!(
+            stream(include_bytes!("testdata/links.tar"), &single),
+            Streamed::Done
+        );
+    }
+
+    #[test]
+    fn falls_back_when_unsorted() {
+        let default = Tar2NarOptions::default();
+        for (paths, path) in [
+            (&["b", "a"][..], "a"),
+            // git sorts directories as if they ended in a slash
+            (&["a-b", "a/x"], "a/x"),
+            (&["a/x", "b", "a/y"], "a/y"),
+        ] {
+            assert_eq!(
+                stream(&files_tar(paths), &default),
+                Streamed::NotStreamable { path: path.into() }
+            );
+        }
+        assert_eq!(
+            stream(&hard_link_tar("root/f"), &strip_root()),
+            Streamed::NotStreamable {
+                path: "root/g".into()
+            }
+        );
+
+        // errors come from the tree builder
+        let tar = files_tar(&["a", "a/x"]);
+        assert!(tar_to_nar_streaming(|| Ok(&tar[..]), Vec::new, &default).is_err());
+    }
+
+    #[test]
+    fn only_owner_exec_bit() {
+        let tar = include_bytes!("testdata/modes.tar");
+        assert_eq!(stream(tar, &strip_root()), Streamed::Done);
+
+        let mut nar = Vec::new();
+        tar_to_nar(Cursor::new(tar), &mut nar, &strip_root()).unwrap();
+        assert_eq!(nar, include_bytes!("testdata/modes.nar"));
+    }
+
+    #[test]
+    fn streams_compressed_input() {
+        let tar = include_bytes!("testdata/test1.tar");
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(tar).unwrap();
+        let gz = gz.finish().unwrap();
+
+        let nar = tar_to_nar_streaming(
+            || Ok(flate2::read::GzDecoder::new(&gz[..])),
+            Vec::new,
+            &Tar2NarOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(nar, include_bytes!("testdata/test1.nar"));
+    }
+
+    #[test]
+    fn nar_tar_round_trip() {
+        for nar in [
+            &include_bytes!("testdata/test1.nar")[..],
+            include_bytes!("testdata/test2.nar"),
+            include_bytes!("testdata/links.nar"),
+        ] {
+            let fso = crate::nar::nar_to_fsobject(nar).unwrap();
+            for (root, strip_root) in [
+                (None, StripRoot::DontStripRoot),
+                (Some(&b"root"[..]), StripRoot::SingleRoot),
+            ] {
+                let mut tar = Vec::new();
+                fsobject_to_tar(&fso, root, &mut tar).unwrap();
+                let options = Tar2NarOptions {
+                    strip_root,
+                    ..Default::default()
+                };
+                let mut again = Vec::new();
+                tar_to_nar(Cursor::new(&tar), &mut again, &options).unwrap();
+                assert_eq!(again, nar);
+            }
+        }
+    }
+
+    #[test]
+    fn deterministic_tar() {
+        let fso = crate::nar::nar_to_fsobject(&include_bytes!("testdata/test2.nar")[..]).unwrap();
+        let mut tar = Vec::new();
+        fsobject_to_tar(&fso, Some(b"root"), &mut tar).unwrap();
+
+        let mut archive = tar::Archive::new(&tar[..]);
+        let mut seen = Vec::new();
+        for member in archive.entries().unwrap() {
+            let member = member.unwrap();
+            let header = member.header();
+            assert_eq!(header.mtime().unwrap(), TAR_MTIME);
+            assert_eq!((header.uid().unwrap(), header.gid().unwrap()), (0, 0));
+            seen.push((
+                String::from_utf8(member.path_bytes().into_owned()).unwrap(),
+                header.mode().unwrap(),
+            ));
+        }
+        assert_eq!(
+            seen,
+            [
+                ("root/".into(), 0o755),
+                ("root/exe".into(), 0o755),
+                ("root/f".into(), 0o644),
+                ("root/f2".into(), 0o777),
+            ]
+        );
+    }
+
+    #[test]
+    fn long_names_and_odd_roots() {
+        let long = "d".repeat(150);
+        let target = format!("./{}//x/", "t".repeat(200));
+        let mut dir = Directory::default();
+        dir.insert(
+            &FileName::try_from(format!("{long}/{long}").as_bytes()).unwrap(),
+            FsObject::Symlink(SymlinkTarget::from(target.as_bytes())),
+        )
+        .unwrap();
+        let fso = FsObject::<ConstByteStream>::Directory(dir);
+
+        let mut tar = Vec::new();
+        fsobject_to_tar(&fso, None, &mut tar).unwrap();
+        assert_eq!(
+            buffered(Cursor::new(&tar), &Default::default()).unwrap(),
+            fso
+        );
+        let mut archive = tar::Archive::new(&tar[..]);
+        for member in archive.entries().unwrap().raw(true) {
+            assert_eq!(member.unwrap().header().mtime().unwrap(), TAR_MTIME);
+        }
+
+        let file = FsObject::File(Executable::IsExecutable, ConstByteStream(b"hi".to_vec()));
+        let mut tar = Vec::new();
+        fsobject_to_tar(&file, Some(b"f"), &mut tar).unwrap();
+        let single = Tar2NarOptions {
+            strip_root: StripRoot::SingleRoot,
+            ..Default::default()
+        };
+        assert_eq!(buffered(Cursor::new(&tar), &single).unwrap(), file);
+
+        assert!(fsobject_to_tar(&file, None, Vec::new()).is_err());
+        assert!(fsobject_to_tar(&file, Some(b".."), Vec::new()).is_err());
+    }
 }
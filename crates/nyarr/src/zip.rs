@@ -0,0 +1,96 @@
+//! Making a nar from a zip file.
+
+use std::io::{Read, Seek, Write};
+
+use crate::tar::{finish_tree, insert_member, StripRoot};
+use crate::{io_error, unk_error, ConstByteStream, Directory, Executable, FsObject, SymlinkTarget};
+use crate::{Error, Tar2NarError};
+
+const S_IFMT: u32 = 0o170000;
+const S_IFLNK: u32 = 0o120000;
+
+/// Reads a zip file through its central directory. Unlike
+/// [`crate::tar::tar_to_fsobject`], which leaves file contents in the archive
+/// until they are serialised, this reads all of them into memory.
+///
+/// Unix permissions and symlinks are taken from the external attributes that
+/// Info-ZIP stores on Unix systems. Archives made elsewhere have neither, so
+/// everything in them is a non-executable file or a directory.
+pub fn zip_to_fsobject(
+    zip: impl Read + Seek,
+    strip_root: StripRoot,
+) -> Result<FsObject<ConstByteStream>, Tar2NarError> {
+    let mut archive = zip::ZipArchive::new(zip).map_err(unk_error)?;
+    let mut tree = Directory::default();
+
+    for i in 0..archive.len() {
+        let mut member = archive.by_index(i).map_err(unk_error)?;
+        let mode = member.unix_mode();
+
+        let obj = if member.is_dir() {
+            FsObject::Directory(Directory::default())
+        } else if mode.is_some_and(|m| m & S_IFMT == S_IFLNK) {
+            let mut target = Vec::new();
+            member.read_to_end(&mut target).map_err(io_error)?;
+            FsObject::Symlink(SymlinkTarget::from(target))
+        } else {
+            let mut v = Vec::new();
+            member.read_to_end(&mut v).map_err(io_error)?;
+
+            FsObject::File(
+                mode.map_or(Executable::NotExecutable, Executable::from_mode),
+                ConstByteStream(v),
+            )
+        };
+
+        insert_member(&mut tree, member.name_raw(), obj, strip_root)?;
+    }
+
+    finish_tree(tree, strip_root)
+}
+
+pub fn zip_to_nar(
+    zip: impl Read + Seek,
+    mut into: impl Write,
+    strip_root: StripRoot,
+) -> Result<(), Error> {
+    let fso = zip_to_fsobject(zip, strip_root)?;
+
+    fso.serialise_toplevel(&mut into)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::basic_tree_2;
+
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn basic_zip() {
+        let mut zipfile = Cursor::new(include_bytes!("testdata/test2.zip"));
+        let fso = zip_to_fsobject(&mut zipfile, StripRoot::StripRoot).unwrap();
+        assert_eq!(fso, basic_tree_2());
+    }
+
+    #[test]
+    fn matches_nix_nar() {
+        let mut zipfile = Cursor::new(include_bytes!("testdata/test2.zip"));
+        let expected = include_bytes!("testdata/test2.nar");
+
+        let mut nar = Vec::new();
+        zip_to_nar(&mut zipfile, &mut nar, StripRoot::StripRoot).unwrap();
+        assert_eq!(nar, expected);
+    }
+
+    #[test]
+    fn only_owner_exec_bit() {
+        let mut zipfile = Cursor::new(include_bytes!("testdata/modes.zip"));
+        let expected = include_bytes!("testdata/modes.nar");
+
+        let mut nar = Vec::new();
+        zip_to_nar(&mut zipfile, &mut nar, StripRoot::StripRoot).unwrap();
+        assert_eq!(nar, expected);
+    }
+}